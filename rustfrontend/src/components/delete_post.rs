@@ -3,6 +3,8 @@ use wasm_bindgen_futures::spawn_local;
 use web_sys::RequestCredentials;
 use yew::prelude::*;
 
+use crate::with_csrf;
+
 #[derive(Clone, Eq, PartialEq, Properties)]
 pub struct Props {
     pub post_id: i64,
@@ -10,7 +12,8 @@ pub struct Props {
 }
 
 async fn delete(url: &str, state: UseStateHandle<String>) {
-    match Request::delete(url)
+    match with_csrf(Request::delete(url))
+        .await
         .credentials(RequestCredentials::Include)
         .send()
         .await
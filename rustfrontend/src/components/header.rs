@@ -1,10 +1,37 @@
+use reqwasm::http::{Request, RequestCredentials};
+use wasm_bindgen_futures::spawn_local;
 use yew::prelude::*;
 use yew_router::prelude::*;
 
-use crate::Route;
+use crate::{model::Profile, Route};
 
 #[function_component(Header)]
 pub fn header() -> Html {
+    let me = use_state_eq(|| None);
+
+    {
+        let me = me.clone();
+
+        use_effect_with_deps(
+            move |_| {
+                spawn_local(async move {
+                    let res = Request::get("http://127.0.0.1:8000/me")
+                        .credentials(RequestCredentials::Include)
+                        .send()
+                        .await;
+
+                    if let Ok(res) = res {
+                        if res.status() == 200 {
+                            me.set(res.json::<Profile>().await.ok());
+                        }
+                    }
+                });
+                || {}
+            },
+            (),
+        );
+    }
+
     html! {
         <>
         <Link<Route> to={Route::AllPosts}>
@@ -18,6 +45,10 @@ pub fn header() -> Html {
         <Link<Route> to={Route::Create}>
             { "create post" }
         </Link<Route>>
+        if let Some(profile) = &*me {
+            <br/>
+            {format!("logged in as {}", profile.display_name.as_ref().unwrap_or(&profile.username))}
+        }
         </>
     }
 }
@@ -1,11 +1,12 @@
 use reqwasm::http::Request;
 use wasm_bindgen_futures::spawn_local;
-use web_sys::{HtmlTextAreaElement, RequestCredentials};
+use web_sys::{HtmlInputElement, HtmlTextAreaElement, RequestCredentials};
 use yew::prelude::*;
 
 use crate::{
     handle_req,
     model::{PostData, PostText},
+    with_csrf,
 };
 
 #[derive(Clone, PartialEq, Eq)]
@@ -24,8 +25,23 @@ pub struct Props {
 #[function_component(MakePost)]
 pub fn make_post(props: &Props) -> Html {
     let status = use_state_eq(String::new);
+    let title = use_state_eq(String::new);
     let text = use_state_eq(String::new);
 
+    // Only posts have titles, replies don't
+    let has_title = matches!(props.action, Action::Create | Action::Edit { .. });
+
+    let title_onchange = {
+        let input_title = title.clone();
+
+        Callback::from(move |e: Event| {
+            let input = e.target_dyn_into::<HtmlInputElement>();
+            if let Some(input) = input {
+                input_title.set(input.value())
+            }
+        })
+    };
+
     let onchange = {
         let input_text = text.clone();
 
@@ -39,7 +55,8 @@ pub fn make_post(props: &Props) -> Html {
 
     let onclick = {
         async fn opts(r: Request, post: PostText, status: UseStateHandle<String>) {
-            let res = r
+            let res = with_csrf(r)
+                .await
                 .body(serde_json::to_string(&post).unwrap())
                 .header("Content-Type", "application/json")
                 .credentials(RequestCredentials::Include)
@@ -56,15 +73,20 @@ pub fn make_post(props: &Props) -> Html {
 
         let action = props.action.clone();
         let click_status = status.clone();
+        let title = title.clone();
         Callback::from(move |_: MouseEvent| {
+            let submit_title = title.clone();
             let submit_text = text.clone();
             let click_status = click_status.clone();
 
             click_status.set(String::from("posting..."));
 
             let post = PostText {
+                title: Some((*submit_title).to_owned())
+                    .filter(|t| has_title && !t.trim().is_empty()),
                 text: (*submit_text).to_owned(),
             };
+            submit_title.set(String::new());
             submit_text.set(String::new());
 
             match action {
@@ -97,6 +119,10 @@ pub fn make_post(props: &Props) -> Html {
 
     html! {
         <>
+            if has_title {
+                <input type="text" placeholder="Title" value={(*title).clone()} onchange={title_onchange}/>
+                <br/>
+            }
             <textarea placeholder="Post text" {onchange}>{ &*text }</textarea>
             <br/>
             <button type="submit" {onclick}>{"Post"}</button>
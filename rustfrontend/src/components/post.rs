@@ -9,6 +9,12 @@ pub struct Props {
     pub post_id: i64,
     pub reply_id: Option<i64>,
     pub username: String,
+    // Posts have a title and maybe a summary, listings leave out their text
+    #[prop_or_default]
+    pub title: Option<String>,
+    #[prop_or_default]
+    pub summary: Option<String>,
+    #[prop_or_default]
     pub text: String,
     pub created_at: NaiveDateTime,
 }
@@ -28,8 +34,11 @@ pub fn post(props: &Props) -> Html {
                 </Link<Route>>
             } else {
                 <Link<Route> to={Route::PostComments { id: props.post_id }}>
-                    {&props.text}
+                    {props.title.as_deref().unwrap_or_default()}
                 </Link<Route>>
+                if let Some(summary) = &props.summary {
+                    {format!(" - {}", summary)}
+                }
                     {format!(" by {} at {} ", &props.username, &props.created_at) }
                 <Link<Route> to={Route::Edit { id: props.post_id }}>
                     {"edit" }
@@ -38,6 +47,10 @@ pub fn post(props: &Props) -> Html {
                 <Link<Route> to={Route::Delete { id: props.post_id }}>
                     {"delete"}
                 </Link<Route>>
+                if !props.text.is_empty() {
+                    <br/>
+                    {&props.text}
+                }
             }
         </p>
     }
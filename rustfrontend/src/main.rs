@@ -9,9 +9,14 @@ use crate::components::{
 };
 use crate::pages::{
     all_posts::AllPosts, auth::Auth, not_found::NotFound, post_comments::PostComments,
+    reset::Reset, two_factor::TwoFactor, verify::Verify,
 };
 
-use reqwasm::{http::Response, Error};
+use model::CsrfToken;
+use reqwasm::{
+    http::{Request, RequestCredentials, Response},
+    Error,
+};
 use wasm_logger;
 use yew::prelude::*;
 use yew_router::prelude::*;
@@ -27,6 +32,13 @@ enum Route {
     NotFound,
     #[at("/auth")]
     Auth,
+    // Targets of the mailed links and the SSO redirect, keep in step with the server
+    #[at("/reset")]
+    Reset,
+    #[at("/verify")]
+    Verify,
+    #[at("/login/2fa")]
+    TwoFactor,
     #[at("/create")]
     Create,
     #[at("/edit/:id")]
@@ -46,6 +58,9 @@ fn switch(switch: &Route) -> Html {
 
         Route::NotFound => html! { <NotFound/> },
         Route::Auth => html! { <Auth/> },
+        Route::Reset => html! { <Reset/> },
+        Route::Verify => html! { <Verify/> },
+        Route::TwoFactor => html! { <TwoFactor/> },
 
         Route::Create => html! { <MakePost action={Action::Create}/> },
         Route::Edit { id } => html! { <MakePost action={Action::Edit {post_id: *id}}/> },
@@ -60,6 +75,24 @@ fn switch(switch: &Route) -> Html {
     }
 }
 
+// Adds the CSRF token the server wants on state-changing requests
+// Fetched every time, since logging in or out changes it
+async fn with_csrf(r: Request) -> Request {
+    let token = match Request::get("http://127.0.0.1:8000/csrf")
+        .credentials(RequestCredentials::Include)
+        .send()
+        .await
+    {
+        Ok(res) if res.status() == 200 => res.json::<CsrfToken>().await.ok().and_then(|t| t.token),
+        _ => None,
+    };
+
+    match token {
+        Some(token) => r.header("X-CSRF-Token", &token),
+        None => r,
+    }
+}
+
 fn handle_req(r: Result<Response, Error>, state: &UseStateHandle<String>) -> Option<Response> {
     match r {
         Ok(res) => match res.status() {
@@ -6,12 +6,25 @@ pub struct PostData {
     pub id: i64,
     pub user_id: i64,
     pub username: String,
+    pub title: String,
+    pub summary: Option<String>,
     pub text: String,
     pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+}
+
+// A page of a listing, next_cursor fetches the following one
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct PostText {
+    // Only posts have titles, edits leave it alone when omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
     pub text: String,
 }
 
@@ -19,11 +32,34 @@ pub struct PostText {
 pub struct CommentData {
     pub id: i64,
     pub post_id: i64,
+    // 0 for replies to the post itself
+    pub depth: i32,
+    // Ids from the top-level reply down to this one, joined by '/'
+    pub path: String,
+    // Author, None on placeholders
     #[allow(dead_code)]
-    user_id: i64,
-    pub username: String,
+    user_id: Option<i64>,
+    pub username: Option<String>,
     pub text: String,
     pub created_at: NaiveDateTime,
+    // Set on placeholders left for replies below a deleted one
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
+impl CommentData {
+    // Orders replies as threads, each followed by the replies below it
+    pub fn thread_key(&self) -> Vec<i64> {
+        self.path
+            .split('/')
+            .filter_map(|id| id.parse().ok())
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub username: String,
+    pub display_name: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -32,9 +68,55 @@ pub struct LoginUser {
     pub password: String,
 }
 
-// Login response
+// Token the server wants on state-changing requests, null when logged out
+#[derive(Debug, Clone, Deserialize)]
+pub struct CsrfToken {
+    pub token: Option<String>,
+}
+
+// A rule a registration field failed
+#[derive(Debug, Clone, Deserialize)]
+pub struct FieldError {
+    pub message: String,
+}
+
+// Login response, registration adds the failed rules
+// and logins with two-factor authentication the challenge to answer
 #[derive(Debug, Clone, Deserialize)]
 pub struct LoginResponse {
     pub status: bool,
     pub message: String,
+    #[serde(default)]
+    pub errors: Vec<FieldError>,
+    #[serde(default)]
+    pub challenge: Option<String>,
+}
+
+// Query of the mailed reset and verification links
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenQuery {
+    pub token: String,
+}
+
+// Query of the two-factor page, set by logins and SSO redirects
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeQuery {
+    pub challenge: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ResetConfirm {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerificationConfirm {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChallengeInput {
+    pub challenge: String,
+    pub code: String,
 }
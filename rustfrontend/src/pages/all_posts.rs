@@ -1,4 +1,8 @@
-use crate::{components::post::Post, handle_req, model::PostData};
+use crate::{
+    components::post::Post,
+    handle_req,
+    model::{Page, PostData},
+};
 
 use reqwasm::http::{Request, RequestCredentials};
 use wasm_bindgen_futures::spawn_local;
@@ -22,8 +26,8 @@ pub fn all_posts() -> Html {
                         .await;
 
                     if let Some(res) = handle_req(res, &state) {
-                        match res.json::<Vec<PostData>>().await {
-                            Ok(o) => posts_data.set(o),
+                        match res.json::<Page<PostData>>().await {
+                            Ok(o) => posts_data.set(o.items),
                             Err(e) => state.set(e.to_string()),
                         }
                     }
@@ -43,7 +47,8 @@ pub fn all_posts() -> Html {
                         <Post
                             post_id={post.id}
                             username={post.username.to_owned()}
-                            text={post.text.to_owned()}
+                            title={post.title.to_owned()}
+                            summary={post.summary.to_owned()}
                             created_at={post.created_at}
                         />
                     </div>
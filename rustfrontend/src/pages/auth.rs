@@ -2,10 +2,12 @@ use reqwasm::http::{Request, RequestCredentials};
 use wasm_bindgen_futures::spawn_local;
 use web_sys::HtmlInputElement;
 use yew::prelude::*;
+use yew_router::prelude::*;
 
 use crate::{
     handle_req,
-    model::{LoginResponse, LoginUser},
+    model::{ChallengeQuery, LoginResponse, LoginUser},
+    with_csrf, Route,
 };
 
 #[function_component(Auth)]
@@ -13,6 +15,7 @@ pub fn auth() -> Html {
     let status = use_state_eq(String::new);
     let username = use_state_eq(String::new);
     let password = use_state_eq(String::new);
+    let history = use_history();
 
     let onchange = |text: UseStateHandle<String>| {
         Callback::from(move |e: Event| {
@@ -27,10 +30,12 @@ pub fn auth() -> Html {
                         username: UseStateHandle<String>,
                         password: UseStateHandle<String>,
                         status: UseStateHandle<String>| {
+        let history = history.clone();
         Callback::from(move |_: MouseEvent| {
             let username = username.clone();
             let password = password.clone();
             let status = status.clone();
+            let history = history.clone();
 
             status.set(String::from("..."));
             let credentials = LoginUser {
@@ -41,7 +46,8 @@ pub fn auth() -> Html {
             password.set(String::new());
 
             spawn_local(async move {
-                let res = Request::post(url)
+                let res = with_csrf(Request::post(url))
+                    .await
                     .body(serde_json::to_string(&credentials).unwrap())
                     .header("Content-Type", "application/json")
                     .credentials(RequestCredentials::Include)
@@ -50,7 +56,28 @@ pub fn auth() -> Html {
 
                 if let Some(res) = handle_req(res, &status) {
                     match res.json::<LoginResponse>().await {
-                        Ok(o) => status.set(o.message),
+                        // Two-factor accounts finish logging in on their own page
+                        Ok(LoginResponse {
+                            challenge: Some(challenge),
+                            ..
+                        }) => match history {
+                            Some(history) => {
+                                if let Err(e) = history
+                                    .push_with_query(Route::TwoFactor, ChallengeQuery { challenge })
+                                {
+                                    status.set(e.to_string())
+                                }
+                            }
+                            None => status.set(String::from("two-factor code needed")),
+                        },
+                        Ok(o) if o.errors.is_empty() => status.set(o.message),
+                        Ok(o) => status.set(
+                            o.errors
+                                .into_iter()
+                                .map(|e| e.message)
+                                .collect::<Vec<_>>()
+                                .join(", "),
+                        ),
                         Err(e) => status.set(e.to_string()),
                     }
                 }
@@ -73,8 +100,11 @@ pub fn auth() -> Html {
                 onclick={onclick("http://127.0.0.1:8000/login", username.clone(), password.clone(), status.clone())}
             >{"Login"}</button>
             <button type="submit"
-                onclick={onclick("http://127.0.0.1:8000/register", username, password, status.clone())}
+                onclick={onclick("http://127.0.0.1:8000/register", username.clone(), password.clone(), status.clone())}
             >{"Register"}</button>
+            <button type="submit"
+                onclick={onclick("http://127.0.0.1:8000/logout", username, password, status.clone())}
+            >{"Logout"}</button>
             {&*status}
         </form>
     }
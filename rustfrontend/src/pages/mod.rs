@@ -2,3 +2,6 @@ pub mod all_posts;
 pub mod auth;
 pub mod not_found;
 pub mod post_comments;
+pub mod reset;
+pub mod two_factor;
+pub mod verify;
@@ -4,7 +4,7 @@ use crate::{
         post::Post,
     },
     handle_req,
-    model::{CommentData, PostData},
+    model::{CommentData, Page, PostData},
 };
 
 use reqwasm::http::{Request, RequestCredentials};
@@ -49,14 +49,20 @@ pub fn post_comments(props: &Props) -> Html {
                     }
                 });
                 spawn_local(async move {
-                    let res = Request::get(&format!("http://127.0.0.1:8000/post/{}/reply/all", id))
-                        .credentials(RequestCredentials::Include)
-                        .send()
-                        .await;
+                    let res = Request::get(&format!(
+                        "http://127.0.0.1:8000/post/{}/reply/all?limit=100",
+                        id
+                    ))
+                    .credentials(RequestCredentials::Include)
+                    .send()
+                    .await;
 
                     if let Some(res) = handle_req(res, &comment_state) {
-                        match res.json::<Vec<CommentData>>().await {
-                            Ok(o) => comment_data.set(o),
+                        match res.json::<Page<CommentData>>().await {
+                            Ok(mut o) => {
+                                o.items.sort_by_key(CommentData::thread_key);
+                                comment_data.set(o.items)
+                            }
                             Err(e) => comment_state.set(e.to_string()),
                         }
                     }
@@ -75,6 +81,8 @@ pub fn post_comments(props: &Props) -> Html {
                     <Post
                     post_id={post.id}
                     username={post.username.to_owned()}
+                    title={post.title.to_owned()}
+                    summary={post.summary.to_owned()}
                     text={post.text.to_owned()}
                     created_at={post.created_at}
                     />
@@ -85,14 +93,18 @@ pub fn post_comments(props: &Props) -> Html {
             {&*comment_status}
             {
                 for (*comments).iter().map(|c| html! {
-                    <div>
-                        <Post
-                            post_id={c.post_id}
-                            reply_id={c.id}
-                            username={c.username.to_owned()}
-                            text={c.text.to_owned()}
-                            created_at={c.created_at}
-                        />
+                    <div style={format!("margin-left: {}em", 2 * c.depth)}>
+                        if c.deleted_at.is_some() {
+                            <p>{"[deleted]"}</p>
+                        } else {
+                            <Post
+                                post_id={c.post_id}
+                                reply_id={c.id}
+                                username={c.username.clone().unwrap_or_default()}
+                                text={c.text.to_owned()}
+                                created_at={c.created_at}
+                            />
+                        }
                     </div>
                 })
             }
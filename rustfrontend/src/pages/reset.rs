@@ -0,0 +1,83 @@
+use reqwasm::http::{Request, RequestCredentials};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+use yew_router::prelude::*;
+
+use crate::{
+    handle_req,
+    model::{LoginResponse, ResetConfirm, TokenQuery},
+    with_csrf,
+};
+
+// Target of the mailed password reset link, /reset?token=
+#[function_component(Reset)]
+pub fn reset() -> Html {
+    let status = use_state_eq(String::new);
+    let password = use_state_eq(String::new);
+    let token = use_location()
+        .and_then(|location| location.query::<TokenQuery>().ok())
+        .map(|query| query.token);
+
+    let onchange = {
+        let password = password.clone();
+        Callback::from(move |e: Event| {
+            let input = e.target_dyn_into::<HtmlInputElement>();
+            if let Some(input) = input {
+                password.set(input.value())
+            }
+        })
+    };
+
+    let token = match token {
+        Some(token) => token,
+        None => return html! { <p>{"This reset link is incomplete"}</p> },
+    };
+
+    let onclick = {
+        let password = password.clone();
+        let status = status.clone();
+        Callback::from(move |_: MouseEvent| {
+            let status = status.clone();
+
+            status.set(String::from("..."));
+            let confirm = ResetConfirm {
+                token: token.clone(),
+                password: (*password).to_owned(),
+            };
+            password.set(String::new());
+
+            spawn_local(async move {
+                let res = with_csrf(Request::post(
+                    "http://127.0.0.1:8000/password-reset/confirm",
+                ))
+                .await
+                .body(serde_json::to_string(&confirm).unwrap())
+                .header("Content-Type", "application/json")
+                .credentials(RequestCredentials::Include)
+                .send()
+                .await;
+
+                if let Some(res) = handle_req(res, &status) {
+                    match res.json::<LoginResponse>().await {
+                        Ok(o) => status.set(o.message),
+                        Err(e) => status.set(e.to_string()),
+                    }
+                }
+            });
+        })
+    };
+
+    html! {
+        <form onsubmit={Callback::from(|e: FocusEvent| e.prevent_default())}>
+            <input type="password" autocomplete="new-password" placeholder="New password" value={(*password).to_owned()}
+                onchange={onchange}/>
+
+            <br/>
+            <br/>
+
+            <button type="submit" onclick={onclick}>{"Reset password"}</button>
+            {&*status}
+        </form>
+    }
+}
@@ -0,0 +1,82 @@
+use reqwasm::http::{Request, RequestCredentials};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+use yew_router::prelude::*;
+
+use crate::{
+    handle_req,
+    model::{ChallengeInput, ChallengeQuery, LoginResponse},
+    with_csrf,
+};
+
+// Second login step for accounts with two-factor authentication, /login/2fa?challenge=
+// Takes a TOTP or recovery code
+#[function_component(TwoFactor)]
+pub fn two_factor() -> Html {
+    let status = use_state_eq(String::new);
+    let code = use_state_eq(String::new);
+    let challenge = use_location()
+        .and_then(|location| location.query::<ChallengeQuery>().ok())
+        .map(|query| query.challenge);
+
+    let onchange = {
+        let code = code.clone();
+        Callback::from(move |e: Event| {
+            let input = e.target_dyn_into::<HtmlInputElement>();
+            if let Some(input) = input {
+                code.set(input.value())
+            }
+        })
+    };
+
+    let challenge = match challenge {
+        Some(challenge) => challenge,
+        None => return html! { <p>{"This login has no challenge, log in again"}</p> },
+    };
+
+    let onclick = {
+        let code = code.clone();
+        let status = status.clone();
+        Callback::from(move |_: MouseEvent| {
+            let status = status.clone();
+
+            status.set(String::from("..."));
+            let input = ChallengeInput {
+                challenge: challenge.clone(),
+                code: (*code).to_owned(),
+            };
+            code.set(String::new());
+
+            spawn_local(async move {
+                let res = with_csrf(Request::post("http://127.0.0.1:8000/login/2fa"))
+                    .await
+                    .body(serde_json::to_string(&input).unwrap())
+                    .header("Content-Type", "application/json")
+                    .credentials(RequestCredentials::Include)
+                    .send()
+                    .await;
+
+                if let Some(res) = handle_req(res, &status) {
+                    match res.json::<LoginResponse>().await {
+                        Ok(o) => status.set(o.message),
+                        Err(e) => status.set(e.to_string()),
+                    }
+                }
+            });
+        })
+    };
+
+    html! {
+        <form onsubmit={Callback::from(|e: FocusEvent| e.prevent_default())}>
+            <input type="text" autocomplete="one-time-code" placeholder="Code" value={(*code).to_owned()}
+                onchange={onchange}/>
+
+            <br/>
+            <br/>
+
+            <button type="submit" onclick={onclick}>{"Log in"}</button>
+            {&*status}
+        </form>
+    }
+}
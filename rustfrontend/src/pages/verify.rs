@@ -0,0 +1,61 @@
+use reqwasm::http::{Request, RequestCredentials};
+use wasm_bindgen_futures::spawn_local;
+use yew::prelude::*;
+use yew_router::prelude::*;
+
+use crate::{
+    handle_req,
+    model::{LoginResponse, TokenQuery, VerificationConfirm},
+    with_csrf,
+};
+
+// Target of the mailed verification link, /verify?token=
+// Waits for a click, so mail scanners opening the link don't use it up
+#[function_component(Verify)]
+pub fn verify() -> Html {
+    let status = use_state_eq(String::new);
+    let token = use_location()
+        .and_then(|location| location.query::<TokenQuery>().ok())
+        .map(|query| query.token);
+
+    let token = match token {
+        Some(token) => token,
+        None => return html! { <p>{"This verification link is incomplete"}</p> },
+    };
+
+    let onclick = {
+        let status = status.clone();
+        Callback::from(move |_: MouseEvent| {
+            let status = status.clone();
+
+            status.set(String::from("..."));
+            let confirm = VerificationConfirm {
+                token: token.clone(),
+            };
+
+            spawn_local(async move {
+                let res = with_csrf(Request::post("http://127.0.0.1:8000/verify-email/confirm"))
+                    .await
+                    .body(serde_json::to_string(&confirm).unwrap())
+                    .header("Content-Type", "application/json")
+                    .credentials(RequestCredentials::Include)
+                    .send()
+                    .await;
+
+                if let Some(res) = handle_req(res, &status) {
+                    match res.json::<LoginResponse>().await {
+                        Ok(o) => status.set(o.message),
+                        Err(e) => status.set(e.to_string()),
+                    }
+                }
+            });
+        })
+    };
+
+    html! {
+        <form onsubmit={Callback::from(|e: FocusEvent| e.prevent_default())}>
+            <button type="submit" onclick={onclick}>{"Verify email"}</button>
+            {&*status}
+        </form>
+    }
+}
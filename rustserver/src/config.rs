@@ -0,0 +1,262 @@
+use std::str::FromStr;
+
+use actix_web::cookie::SameSite;
+use argon2::Params;
+use chrono::Duration;
+use rand::RngCore;
+use url::Url;
+
+use crate::model::audit;
+
+// Server settings, read from environment variables with sensible defaults
+#[derive(Debug, Clone)]
+pub struct Config {
+    // How long a freshly issued token stays valid
+    pub token_lifetime: Duration,
+    // Tokens unused for this long are rejected, None disables the check
+    pub token_idle_timeout: Option<Duration>,
+    // Tokens used within this long of expiring are renewed for another lifetime
+    pub token_renew_window: Duration,
+    // How often expired tokens are purged from the database
+    pub cleanup_interval: std::time::Duration,
+    // Audit log entries older than this are purged, None keeps them forever
+    pub audit_retention: Option<Duration>,
+    // Server secret keying the token digests
+    pub token_secret: Vec<u8>,
+    // Whether tokens stored under the old bcrypt digest are still accepted
+    pub legacy_token_hashes: bool,
+    // Argon2id cost parameters for password hashes
+    pub password_params: Params,
+    // How long a mailed password reset link stays valid
+    pub reset_token_lifetime: Duration,
+    // How long a mailed email verification link stays valid
+    pub verification_token_lifetime: Duration,
+    // Whether new accounts need an email address and stay pending until it is verified
+    pub require_email_verification: bool,
+    // Where mail goes, "outbox" for files in mail_outbox_dir or "log" for standard output
+    pub mail_sink: String,
+    // Directory the outbox mailer writes messages to
+    pub mail_outbox_dir: String,
+    // Base URL of the frontend, used for links in mail
+    pub frontend_url: String,
+    // Origins allowed to make credentialed cross-origin requests and state-changing requests
+    pub allowed_origins: Vec<String>,
+    // SameSite attribute of the token cookie
+    pub cookie_same_site: SameSite,
+    // User promoted to admin on startup
+    pub admin_username: Option<String>,
+    // Failed logins a username tolerates before it is locked out
+    pub login_max_failures: i32,
+    // Failed logins a client IP tolerates before it is locked out
+    pub login_max_failures_per_ip: i32,
+    // First lockout, doubled with every further failure
+    pub login_lockout: Duration,
+    // Longest lockout
+    pub login_max_lockout: Duration,
+    // Failures older than this are forgotten
+    pub login_failure_window: Duration,
+    // How long a password-verified login waits for its second factor
+    pub login_challenge_lifetime: Duration,
+    // Whether admins must have two-factor authentication enabled to act as admins
+    pub require_admin_2fa: bool,
+    // Allowed length of new usernames, in characters
+    pub username_min_len: usize,
+    pub username_max_len: usize,
+    // Characters allowed in usernames besides ASCII letters and digits
+    pub username_extra_chars: String,
+    // Lowercase usernames nobody may register
+    pub reserved_usernames: Vec<String>,
+    // Who may register with POST /register
+    pub registration_mode: RegistrationMode,
+    // Most uses an invite made by a non-admin may have
+    pub invite_max_uses: i32,
+    // Longest an invite made by a non-admin may stay valid
+    pub invite_max_days: i64,
+    // Slug of the board posts go to when none is given, created on startup
+    pub default_board: String,
+    // Single sign-on through an OpenID Connect provider, None when not configured
+    pub oidc: Option<OidcConfig>,
+    // Whether users may register and log in with a password, always true without SSO
+    pub password_login: bool,
+    // Shortest password accepted, in characters
+    pub password_min_len: usize,
+    // How many of lowercase, uppercase, digits and symbols a password must mix
+    pub password_min_classes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationMode {
+    Open,
+    // Registering needs an invite code from an existing user
+    InviteOnly,
+    Closed,
+}
+
+// OpenID Connect client settings
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    // Issuer URL, discovery is fetched from below it
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    // Where the provider sends the browser back to, ends in /oidc/callback
+    pub redirect_url: String,
+    pub scopes: String,
+    // Whether a first SSO login may claim an existing account with the same verified email
+    pub link_by_email: bool,
+    // Accept plain http provider URLs, only meant for a local mock IdP
+    pub allow_insecure_http: bool,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let idle_hours = env_or("TOKEN_IDLE_TIMEOUT_HOURS", 24 * 7);
+
+        let cleanup_minutes: u64 = env_or("CLEANUP_INTERVAL_MINUTES", 60);
+        if cleanup_minutes == 0 {
+            eprintln!("CLEANUP_INTERVAL_MINUTES must be at least 1, using 1");
+        }
+
+        let password_params = Params::new(
+            env_or("ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST),
+            env_or("ARGON2_ITERATIONS", Params::DEFAULT_T_COST),
+            env_or("ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+            None,
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("invalid Argon2 parameters ({}), using defaults", e);
+            Params::default()
+        });
+
+        let token_secret = match std::env::var("TOKEN_SECRET") {
+            Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+            _ => {
+                eprintln!("TOKEN_SECRET is not set, logins will not survive a restart");
+                let mut secret = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+
+        let oidc = match (
+            std::env::var("OIDC_ISSUER"),
+            std::env::var("OIDC_CLIENT_ID"),
+        ) {
+            (Ok(issuer), Ok(client_id)) if !issuer.is_empty() && !client_id.is_empty() => {
+                Some(OidcConfig {
+                    issuer,
+                    client_id,
+                    client_secret: std::env::var("OIDC_CLIENT_SECRET")
+                        .ok()
+                        .filter(|s| !s.is_empty()),
+                    redirect_url: env_or(
+                        "OIDC_REDIRECT_URL",
+                        String::from("http://127.0.0.1:8000/oidc/callback"),
+                    ),
+                    scopes: env_or("OIDC_SCOPES", String::from("openid profile email")),
+                    link_by_email: env_or("OIDC_LINK_BY_EMAIL", false),
+                    allow_insecure_http: env_or("OIDC_ALLOW_INSECURE_HTTP", false),
+                })
+            }
+            _ => None,
+        };
+
+        let audit_retention_days = env_or("AUDIT_RETENTION_DAYS", 0);
+        if audit_retention_days > 0 && audit_retention_days < audit::MIN_RETENTION_DAYS {
+            eprintln!(
+                "AUDIT_RETENTION_DAYS is below {}, keeping the audit log that long",
+                audit::MIN_RETENTION_DAYS
+            );
+        }
+
+        let password_login = env_or("PASSWORD_LOGIN", true);
+        if !password_login && oidc.is_none() {
+            eprintln!("PASSWORD_LOGIN is off but SSO is not configured, keeping password login");
+        }
+
+        let frontend_url = env_or("FRONTEND_URL", String::from("http://127.0.0.1:8080"));
+
+        let allowed_origins = env_or("ALLOWED_ORIGINS", frontend_url.clone())
+            .split(',')
+            .filter_map(|origin| Url::parse(origin.trim()).ok())
+            .map(|url| url.origin().ascii_serialization())
+            .collect();
+
+        let cookie_same_site = match env_or("COOKIE_SAME_SITE", String::from("lax"))
+            .to_lowercase()
+            .as_str()
+        {
+            "strict" => SameSite::Strict,
+            "none" => SameSite::None,
+            _ => SameSite::Lax,
+        };
+
+        let registration_mode = match env_or("REGISTRATION_MODE", String::from("open"))
+            .to_lowercase()
+            .as_str()
+        {
+            "invite" | "invite-only" | "invite_only" => RegistrationMode::InviteOnly,
+            "closed" => RegistrationMode::Closed,
+            _ => RegistrationMode::Open,
+        };
+
+        Config {
+            token_lifetime: Duration::hours(env_or("TOKEN_LIFETIME_HOURS", 24 * 30)),
+            token_idle_timeout: Some(Duration::hours(idle_hours)).filter(|_| idle_hours > 0),
+            token_renew_window: Duration::hours(env_or("TOKEN_RENEW_WINDOW_HOURS", 24 * 7)),
+            cleanup_interval: std::time::Duration::from_secs(cleanup_minutes.max(1) * 60),
+            audit_retention: Some(Duration::days(
+                audit_retention_days.max(audit::MIN_RETENTION_DAYS),
+            ))
+            .filter(|_| audit_retention_days > 0),
+            token_secret,
+            legacy_token_hashes: env_or("LEGACY_TOKEN_HASHES", true),
+            password_params,
+            reset_token_lifetime: Duration::minutes(env_or("RESET_TOKEN_MINUTES", 30)),
+            verification_token_lifetime: Duration::hours(env_or("VERIFICATION_TOKEN_HOURS", 48)),
+            require_email_verification: env_or("REQUIRE_EMAIL_VERIFICATION", false),
+            mail_sink: env_or("MAIL_SINK", String::from("outbox")),
+            mail_outbox_dir: env_or("MAIL_OUTBOX_DIR", String::from("outbox")),
+            frontend_url,
+            allowed_origins,
+            cookie_same_site,
+            admin_username: std::env::var("ADMIN_USERNAME")
+                .ok()
+                .filter(|u| !u.is_empty()),
+            login_max_failures: env_or("LOGIN_MAX_FAILURES", 5),
+            login_max_failures_per_ip: env_or("LOGIN_MAX_FAILURES_PER_IP", 20),
+            login_lockout: Duration::seconds(env_or("LOGIN_LOCKOUT_SECONDS", 30)),
+            login_max_lockout: Duration::seconds(env_or("LOGIN_MAX_LOCKOUT_SECONDS", 60 * 60)),
+            login_failure_window: Duration::minutes(env_or("LOGIN_FAILURE_WINDOW_MINUTES", 60)),
+            login_challenge_lifetime: Duration::minutes(env_or("LOGIN_CHALLENGE_MINUTES", 5)),
+            require_admin_2fa: env_or("REQUIRE_ADMIN_2FA", true),
+            username_min_len: env_or("USERNAME_MIN_LENGTH", 3),
+            username_max_len: env_or("USERNAME_MAX_LENGTH", 32),
+            username_extra_chars: env_or("USERNAME_EXTRA_CHARS", String::from("_-.")),
+            reserved_usernames: env_or(
+                "RESERVED_USERNAMES",
+                String::from("admin,administrator,moderator,root,system,support,me,anonymous"),
+            )
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect(),
+            registration_mode,
+            default_board: env_or("DEFAULT_BOARD", String::from("general")),
+            invite_max_uses: env_or("INVITE_MAX_USES", 5),
+            invite_max_days: env_or("INVITE_MAX_DAYS", 30),
+            password_login: password_login || oidc.is_none(),
+            oidc,
+            password_min_len: env_or("PASSWORD_MIN_LENGTH", 8),
+            password_min_classes: env_or("PASSWORD_MIN_CLASSES", 2),
+        }
+    }
+}
+
+// Parses an environment variable, falling back to default when unset or invalid
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
@@ -0,0 +1,135 @@
+use actix_web::{
+    error::InternalError,
+    web::{Data, Json, Path},
+    HttpRequest, HttpResponse,
+};
+use chrono::{Duration, Utc};
+use sea_orm::{
+    prelude::Uuid, ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait,
+    QueryFilter, QueryOrder, Set,
+};
+
+use crate::{config::Config, crypto::token_digest, model::token};
+
+use super::{
+    client_ip, permission::check_session, to_bad_request, to_internal_error, to_not_found, to_ok,
+    user_agent,
+};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_EXPIRES_IN_DAYS: i64 = 365;
+
+// POST /me/tokens
+// Takes in JSON encoded token AccessTokenInput and session auth
+// On success, returns 200 OK with JSON encoded token CreatedAccessToken, the only time
+// the secret is returned
+// If the input is invalid, returns 400 Bad Request
+pub async fn create(
+    Json(input): Json<token::AccessTokenInput>,
+    req: HttpRequest,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<Json<token::CreatedAccessToken>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let name = input.name.trim().to_owned();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(to_bad_request(DbErr::Custom(format!(
+            "name must be 1 to {} characters",
+            MAX_NAME_LEN
+        ))));
+    }
+
+    if input.scopes.is_empty() {
+        return Err(to_bad_request(DbErr::Custom(
+            "at least one scope is required".to_string(),
+        )));
+    }
+
+    let expires_in_days = input.expires_in_days.unwrap_or(MAX_EXPIRES_IN_DAYS);
+    if !(1..=MAX_EXPIRES_IN_DAYS).contains(&expires_in_days) {
+        return Err(to_bad_request(DbErr::Custom(format!(
+            "expires_in_days must be 1 to {}",
+            MAX_EXPIRES_IN_DAYS
+        ))));
+    }
+
+    let mut scopes: Vec<&str> = input.scopes.iter().map(|s| s.as_str()).collect();
+    scopes.sort_unstable();
+    scopes.dedup();
+
+    let uuid = Uuid::new_v4();
+    let now = Utc::now();
+
+    let access_token = token::ActiveModel {
+        hash: Set(token_digest(&config.token_secret, uuid.as_bytes())),
+        user_id: Set(token.user_id),
+        expires_at: Set((now + Duration::days(expires_in_days)).naive_utc()),
+        created_at: Set(now.naive_utc()),
+        last_used_at: Set(now.naive_utc()),
+        user_agent: Set(user_agent(&req)),
+        ip: Set(client_ip(&req)),
+        name: Set(Some(name)),
+        scopes: Set(Some(scopes.join(" "))),
+        ..Default::default()
+    }
+    .insert(db.as_ref())
+    .await
+    .map_err(to_internal_error)?;
+
+    Ok(Json(token::CreatedAccessToken {
+        access_token: access_token.to_access_token(),
+        token: token::format_access_token(uuid),
+    }))
+}
+
+// GET /me/tokens
+// Takes in session auth
+// On success, returns 200 OK with JSON encoded token AccessTokens of the user
+// On error, returns 500 Internal Server Error
+pub async fn read_all(
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<Json<Vec<token::AccessToken>>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    token::Entity::find()
+        .filter(token::Column::UserId.eq(token.user_id))
+        .filter(token::Column::Scopes.is_not_null())
+        .filter(token::Column::ExpiresAt.gt(Utc::now().naive_utc()))
+        .order_by_desc(token::Column::CreatedAt)
+        .all(db.as_ref())
+        .await
+        .map(|tokens| tokens.iter().map(token::Model::to_access_token).collect())
+        .map(Json)
+        .map_err(to_internal_error)
+}
+
+// DELETE /me/tokens/{token_id}
+// Takes in session auth
+// On success, revokes the access token and returns 200 OK
+// If token_id does not exist for the user, returns 404 Not Found
+pub async fn delete(
+    param: Path<i64>,
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<HttpResponse, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let token_id = param.into_inner();
+
+    let res = token::Entity::delete_many()
+        .filter(token::Column::Id.eq(token_id))
+        .filter(token::Column::UserId.eq(token.user_id))
+        .filter(token::Column::Scopes.is_not_null())
+        .exec(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    if res.rows_affected == 0 {
+        return Err(to_not_found(DbErr::RecordNotFound(String::new())));
+    }
+
+    Ok(to_ok(res))
+}
@@ -0,0 +1,103 @@
+use actix_web::{
+    error::InternalError,
+    web::{Data, Json, Query},
+    HttpRequest,
+};
+use chrono::Utc;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait,
+    QueryFilter, QueryOrder, QuerySelect, Set,
+};
+
+use crate::{
+    config::Config,
+    model::{audit, token},
+};
+
+use super::{
+    client_ip,
+    permission::{actor, check_admin, check_session},
+    to_internal_error, user_agent,
+};
+
+const DEFAULT_LIMIT: u64 = 100;
+const MAX_LIMIT: u64 = 1000;
+
+// Appends an event to the audit log
+// Failing to record is reported but never fails the request being audited
+pub async fn record(
+    db: &DatabaseConnection,
+    req: &HttpRequest,
+    event: &str,
+    user_id: Option<i64>,
+    username: Option<&str>,
+    detail: Option<&str>,
+) {
+    let res = audit::ActiveModel {
+        event: Set(event.to_owned()),
+        user_id: Set(user_id),
+        username: Set(username.map(str::to_owned)),
+        ip: Set(client_ip(req)),
+        user_agent: Set(user_agent(req)),
+        detail: Set(detail.map(str::to_owned)),
+        created_at: Set(Utc::now().naive_utc()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await;
+
+    if let Err(e) = res {
+        eprintln!("failed to record {} in the audit log: {}", event, e);
+    }
+}
+
+// GET /audit
+// Takes in query encoded audit AuditQuery and admin auth
+// On success, returns 200 OK with JSON encoded audit log entries matching every given
+// filter, newest first
+// If the caller is not an admin, returns 403 Forbidden
+pub async fn read_all(
+    Query(query): Query<audit::AuditQuery>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<Json<Vec<audit::Model>>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let actor = actor(db.as_ref(), &token).await?;
+    check_admin(&actor, &config)?;
+
+    let mut select = audit::Entity::find();
+    if let Some(event) = query.event {
+        select = select.filter(audit::Column::Event.eq(event));
+    }
+    if let Some(username) = query.username {
+        select = select.filter(Expr::cust_with_values(
+            "LOWER(username) = ?",
+            vec![username.to_lowercase()],
+        ));
+    }
+    if let Some(user_id) = query.user_id {
+        select = select.filter(audit::Column::UserId.eq(user_id));
+    }
+    if let Some(ip) = query.ip {
+        select = select.filter(audit::Column::Ip.eq(ip));
+    }
+    if let Some(since) = query.since {
+        select = select.filter(audit::Column::CreatedAt.gte(since));
+    }
+    if let Some(until) = query.until {
+        select = select.filter(audit::Column::CreatedAt.lt(until));
+    }
+    if let Some(before) = query.before {
+        select = select.filter(audit::Column::Id.lt(before));
+    }
+
+    select
+        .order_by_desc(audit::Column::Id)
+        .limit(query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT))
+        .all(db.as_ref())
+        .await
+        .map(Json)
+        .map_err(to_internal_error)
+}
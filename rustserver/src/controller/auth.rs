@@ -1,130 +1,640 @@
 use actix_web::{
-    cookie::{Cookie, SameSite},
-    dev::Payload,
+    body::MessageBody,
+    cookie::{time::Duration as CookieDuration, Cookie},
+    dev::{Payload, ServiceRequest, ServiceResponse},
     error::InternalError,
-    http::StatusCode,
-    web::{Data, Json},
-    FromRequest, HttpRequest, HttpResponse,
+    http::{
+        header::{AUTHORIZATION, RETRY_AFTER},
+        StatusCode,
+    },
+    middleware::Next,
+    web::{self, Data, Json},
+    FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
 };
-use bcrypt::{hash, hash_with_salt, verify, DEFAULT_COST};
 use chrono::Utc;
 use futures::{future, Future, FutureExt};
 use sea_orm::{
-    prelude::Uuid, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
-    QueryFilter, Set,
+    prelude::Uuid, sea_query::Expr, ActiveModelTrait, ColumnTrait, ConnectionTrait,
+    DatabaseConnection, DbErr, EntityTrait, ModelTrait, QueryFilter, Set,
 };
 
-use crate::model::{
-    token,
-    user::{self, LoginResponse},
+use crate::{
+    config::{Config, RegistrationMode},
+    crypto::{hash_password, legacy_token_digest, needs_rehash, token_digest, verify_password},
+    mail::Mailer,
+    model::{
+        audit as audit_event, token,
+        user::{self, FieldError, LoginResponse, RegisterResponse, Role, Status},
+    },
+};
+
+use super::{
+    audit, client_ip, hash_blocking, invite, lockout, permission::check_session, to_internal_error,
+    two_factor, user_agent, validation, verification::send_verification,
 };
 
 // POST /register
-// Takes in JSON encoded user Input
-// On success, returns 200 OK with JSON encoded LoginResponse
+// Takes in JSON encoded user Registration
+// On success, returns 200 OK with JSON encoded RegisterResponse
+// If email verification is required, the account stays pending until the mailed link is used
+// If the input breaks the registration rules, returns 200 OK with JSON encoded
+// RegisterResponse listing every failed rule per field
+// While registration is invite-only, a usable invite code is one of the rules
+// If registration is closed or password login is disabled, returns 403 Forbidden
 // On error, returns 500 Internal Server Error
 pub async fn create(
-    Json(mut input_user): Json<user::Input>,
+    Json(mut input): Json<user::Registration>,
+    req: HttpRequest,
     db: Data<DatabaseConnection>,
-) -> Json<LoginResponse> {
-    input_user.password = match hash(input_user.password, DEFAULT_COST) {
-        Ok(p) => p,
-        Err(_) => {
-            return Json(LoginResponse {
+    config: Data<Config>,
+    mailer: Data<dyn Mailer>,
+) -> Result<Json<RegisterResponse>, InternalError<DbErr>> {
+    check_password_login(&config)?;
+    check_registration_open(&config)?;
+
+    input.email = input
+        .email
+        .map(|email| email.trim().to_lowercase())
+        .filter(|email| !email.is_empty());
+    input.invite = input
+        .invite
+        .map(|code| code.trim().to_owned())
+        .filter(|code| !code.is_empty());
+
+    let mut errors = validation::username(&config, &input.username);
+    errors.extend(validation::password(
+        &config,
+        &input.password,
+        &input.username,
+    ));
+    match &input.email {
+        Some(email) => errors.extend(validation::email(email)),
+        None if config.require_email_verification => errors.push(FieldError {
+            field: "email",
+            code: "required",
+            message: String::from("email is required"),
+        }),
+        None => {}
+    }
+    if input.invite.is_none() && config.registration_mode == RegistrationMode::InviteOnly {
+        errors.push(FieldError {
+            field: "invite",
+            code: "required",
+            message: String::from("an invite is required"),
+        });
+    }
+
+    let mut invite = None;
+    if errors.is_empty() {
+        errors = taken(db.get_ref(), &input).await?;
+
+        if let Some(code) = &input.invite {
+            invite = invite::find_usable(db.get_ref(), code)
+                .await
+                .map_err(to_internal_error)?;
+            if invite.is_none() {
+                errors.push(invite::unusable());
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Ok(Json(RegisterResponse {
+            status: false,
+            message: "invalid registration info",
+            errors,
+        }));
+    }
+
+    let password = hash_blocking(&input.password, &config.password_params).await?;
+
+    // Claiming the invite and creating the user succeed or fail together
+    let txn = db.begin().await.map_err(to_internal_error)?;
+
+    if let Some(invite) = &invite {
+        if !invite::claim(&txn, invite.id)
+            .await
+            .map_err(to_internal_error)?
+        {
+            return Ok(Json(RegisterResponse {
                 status: false,
                 message: "invalid registration info",
-            })
+                errors: vec![invite::unusable()],
+            }));
         }
-    };
+    }
 
-    let input_user = input_user.into_active_model();
-    let input_user = user::ActiveModel {
+    let inserted = user::ActiveModel {
+        username: Set(input.username),
+        password: Set(password),
+        email: Set(input.email),
+        role: Set(Role::User),
+        totp_enabled: Set(false),
+        status: Set(if config.require_email_verification {
+            Status::PendingVerification
+        } else {
+            Status::Active
+        }),
+        invited_by: Set(invite.as_ref().map(|invite| invite.created_by)),
+        invite_id: Set(invite.as_ref().map(|invite| invite.id)),
         created_at: Set(Utc::now().naive_utc()),
-        ..input_user
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await;
+
+    // A registration racing this one may have taken the username or email since taken()
+    let user = match inserted {
+        Ok(user) => user,
+        Err(e) => {
+            let message = e.to_string();
+            let error = if message.contains("idx-user-username-lower")
+                || message.contains("users_username_key")
+            {
+                username_taken_error()
+            } else if message.contains("idx-user-email") || message.contains("users_email_key") {
+                email_taken_error()
+            } else {
+                return Err(to_internal_error(e));
+            };
+
+            return Ok(Json(RegisterResponse {
+                status: false,
+                message: "invalid registration info",
+                errors: vec![error],
+            }));
+        }
     };
 
-    match input_user.insert(db.get_ref()).await {
-        Ok(_) => Json(LoginResponse {
+    txn.commit().await.map_err(to_internal_error)?;
+
+    let detail = invite.map(|invite| format!("invite {}", invite.id));
+    audit::record(
+        db.get_ref(),
+        &req,
+        audit_event::REGISTER,
+        Some(user.id),
+        Some(&user.username),
+        detail.as_deref(),
+    )
+    .await;
+
+    if user.status == Status::PendingVerification {
+        send_verification(db.get_ref(), &config, mailer.as_ref(), &user).await?;
+
+        return Ok(Json(RegisterResponse {
             status: true,
-            message: "registration successful",
-        }),
-        Err(_) => Json(LoginResponse {
-            status: false,
-            message: "username is taken",
-        }),
+            message: "registration successful, check your email to verify your account",
+            errors: Vec::new(),
+        }));
     }
+
+    Ok(Json(RegisterResponse {
+        status: true,
+        message: "registration successful",
+        errors: Vec::new(),
+    }))
+}
+
+// Checks whether a user has the username, ignoring case
+pub async fn username_taken(db: &DatabaseConnection, username: &str) -> Result<bool, DbErr> {
+    user::Entity::find()
+        .filter(Expr::cust_with_values(
+            "LOWER(username) = ?",
+            vec![username.to_lowercase()],
+        ))
+        .one(db)
+        .await
+        .map(|user| user.is_some())
+}
+
+fn username_taken_error() -> FieldError {
+    FieldError {
+        field: "username",
+        code: "taken",
+        message: String::from("username is taken"),
+    }
+}
+
+fn email_taken_error() -> FieldError {
+    FieldError {
+        field: "email",
+        code: "taken",
+        message: String::from("email is already in use"),
+    }
+}
+
+// Checks the username, ignoring case, and the email address are still free
+async fn taken(
+    db: &DatabaseConnection,
+    input: &user::Registration,
+) -> Result<Vec<FieldError>, InternalError<DbErr>> {
+    let mut errors = Vec::new();
+
+    if username_taken(db, &input.username)
+        .await
+        .map_err(to_internal_error)?
+    {
+        errors.push(username_taken_error());
+    }
+
+    if let Some(email) = &input.email {
+        let email_taken = user::Entity::find()
+            .filter(user::Column::Email.eq(email.as_str()))
+            .one(db)
+            .await
+            .map_err(to_internal_error)?
+            .is_some();
+        if email_taken {
+            errors.push(email_taken_error());
+        }
+    }
+
+    Ok(errors)
 }
 
 // POST /login
 // Takes in JSON encoded user Input
 // On success, returns 200 OK with JSON encoded LoginResponse and cookie
+// If the user has two-factor authentication, returns 200 OK with JSON encoded
+// ChallengeResponse to complete with POST /login/2fa
+// On bad credentials, returns 200 OK with the same LoginResponse whatever was wrong
+// While the username or client IP is locked out, returns 429 Too Many Requests
+// If the account is pending verification, suspended or banned, returns 403 Forbidden
+// with JSON encoded LoginResponse saying so
+// If password login is disabled, returns 403 Forbidden
 // On error, returns 500 Internal Server Error
 pub async fn login(
     Json(login_user): Json<user::Input>,
+    req: HttpRequest,
     db: Data<DatabaseConnection>,
+    config: Data<Config>,
 ) -> HttpResponse {
+    if let Err(e) = check_password_login(&config) {
+        return e.error_response();
+    }
+
+    let ip = client_ip(&req);
+    let keys = lockout::keys(&config, &login_user.username, ip.as_deref());
+
+    let locked_until = match lockout::locked_until(db.get_ref(), &keys).await {
+        Ok(locked_until) => locked_until,
+        Err(e) => return to_internal_error(e).error_response(),
+    };
+    if let Some(until) = locked_until {
+        audit::record(
+            db.get_ref(),
+            &req,
+            audit_event::LOGIN_LOCKED,
+            None,
+            Some(&login_user.username),
+            None,
+        )
+        .await;
+        return HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+            .insert_header((RETRY_AFTER, lockout::retry_after(until).to_string()))
+            .json(LoginResponse {
+                status: false,
+                message: "too many failed logins, try again later",
+            });
+    }
+
     let user = match user::Entity::find()
-        .filter(user::Column::Username.eq(login_user.username))
+        .filter(user::Column::Username.eq(login_user.username.as_str()))
         .one(db.get_ref())
         .await
     {
-        Ok(Some(u)) => u,
-        Err(_) | Ok(None) => {
+        Ok(user) => user,
+        Err(e) => return to_internal_error(e).error_response(),
+    };
+
+    // Password hashing is slow on purpose, so it runs on the blocking thread pool
+    // Spend the same effort on unknown usernames, so timing doesn't reveal them
+    let verified = {
+        let password = login_user.password.clone();
+        let stored = user.as_ref().map(|user| user.password.clone());
+        let params = config.password_params.clone();
+
+        match web::block(move || match stored {
+            Some(stored) => verify_password(&password, &stored),
+            None => {
+                let _ = hash_password(&password, &params);
+                false
+            }
+        })
+        .await
+        {
+            Ok(verified) => verified,
+            Err(e) => return e.error_response(),
+        }
+    };
+
+    let user = match (user, verified) {
+        (Some(user), true) => user,
+        (user, _) => {
+            let _ = lockout::record_failure(db.get_ref(), &config, &keys).await;
+            audit::record(
+                db.get_ref(),
+                &req,
+                audit_event::LOGIN_FAILURE,
+                user.as_ref().map(|user| user.id),
+                Some(&login_user.username),
+                Some(if user.is_some() {
+                    "wrong password"
+                } else {
+                    "unknown username"
+                }),
+            )
+            .await;
             return HttpResponse::build(StatusCode::OK).json(LoginResponse {
                 status: false,
-                message: "username doesn't exist",
-            })
+                message: "invalid login info",
+            });
         }
     };
 
-    if let Some(true) = verify(login_user.password, &user.password).ok() {
-        // Build response
-        let mut response = HttpResponse::build(StatusCode::OK).json(LoginResponse {
-            status: true,
-            message: "login successful",
+    if let Some(message) = user.status_error(Utc::now().naive_utc()) {
+        audit::record(
+            db.get_ref(),
+            &req,
+            audit_event::LOGIN_FAILURE,
+            Some(user.id),
+            Some(&user.username),
+            Some(message),
+        )
+        .await;
+        return HttpResponse::build(StatusCode::FORBIDDEN).json(LoginResponse {
+            status: false,
+            message,
         });
+    }
 
-        // Generate token id and add to response as cookie
-        let uuid = Uuid::new_v4();
+    // Upgrade the stored hash now that the plaintext is at hand
+    if needs_rehash(&user.password, &config.password_params) {
+        let params = config.password_params.clone();
+        let rehashed = web::block(move || hash_password(&login_user.password, &params)).await;
 
-        let hash = hash_with_salt(uuid.as_bytes(), 4, &[0; 16][..])
-            .unwrap()
-            .to_string();
-
-        let mut buf = [b'x'; 36];
-        let str = uuid.to_hyphenated().encode_lower(&mut buf);
-
-        let cookie = Cookie::build("token", &*str)
-            .secure(true)
-            .permanent()
-            .same_site(SameSite::None)
-            .finish();
-        response.add_cookie(&cookie).unwrap();
-
-        // Set expiry
-        let expiry = chrono::Utc::now() + chrono::Duration::weeks(1240);
-
-        // Build
-        let token = token::Model {
-            hash,
-            user_id: user.id,
-            expires_at: expiry.naive_utc(),
-        };
+        if let Ok(Ok(password)) = rehashed {
+            let _ = user::ActiveModel {
+                id: Set(user.id),
+                password: Set(password),
+                ..Default::default()
+            }
+            .update(db.get_ref())
+            .await;
+        }
+    }
+
+    if user.totp_enabled {
+        audit::record(
+            db.get_ref(),
+            &req,
+            audit_event::LOGIN_CHALLENGE,
+            Some(user.id),
+            Some(&user.username),
+            None,
+        )
+        .await;
+        return two_factor::challenge(db.get_ref(), &config, user.id).await;
+    }
+
+    // With two-factor authentication, failures are only forgotten once the code is right
+    let _ = lockout::record_success(db.get_ref(), &user.username).await;
+
+    audit::record(
+        db.get_ref(),
+        &req,
+        audit_event::LOGIN_SUCCESS,
+        Some(user.id),
+        Some(&user.username),
+        Some("password"),
+    )
+    .await;
+
+    issue_token(&req, db.get_ref(), &config, user.id).await
+}
+
+// Creates a session token for the user
+// Returns 200 OK with JSON encoded LoginResponse and the token cookie
+// If the token can't be stored, returns 500 Internal Server Error
+pub async fn issue_token(
+    req: &HttpRequest,
+    db: &DatabaseConnection,
+    config: &Config,
+    user_id: i64,
+) -> HttpResponse {
+    let cookie = match new_session(req, db, config, user_id).await {
+        Ok(cookie) => cookie,
+        Err(e) => return e.error_response(),
+    };
+
+    let mut response = HttpResponse::build(StatusCode::OK).json(LoginResponse {
+        status: true,
+        message: "login successful",
+    });
+    response.add_cookie(&cookie).unwrap();
+
+    response
+}
 
-        // Put token in database
-        let _ = token.into_active_model().insert(db.get_ref()).await;
+// Creates a session token for the user, returning the cookie carrying it
+pub async fn new_session(
+    req: &HttpRequest,
+    db: &DatabaseConnection,
+    config: &Config,
+    user_id: i64,
+) -> Result<Cookie<'static>, InternalError<DbErr>> {
+    // Generate token id and its cookie
+    let uuid = Uuid::new_v4();
 
-        response
+    let hash = token_digest(&config.token_secret, uuid.as_bytes());
+
+    let cookie = token_cookie(uuid, config);
+
+    // Set expiry
+    let now = chrono::Utc::now();
+    let expiry = now + config.token_lifetime;
+
+    // Build
+    let token = token::ActiveModel {
+        hash: Set(hash),
+        user_id: Set(user_id),
+        expires_at: Set(expiry.naive_utc()),
+        created_at: Set(now.naive_utc()),
+        last_used_at: Set(now.naive_utc()),
+        user_agent: Set(user_agent(req)),
+        ip: Set(client_ip(req)),
+        ..Default::default()
+    };
+
+    // Put token in database
+    token.insert(db).await.map_err(to_internal_error)?;
+
+    Ok(cookie)
+}
+
+// Checks new accounts may be created, by registering or a first SSO login
+// If registration is closed, fails with 403 Forbidden
+pub fn check_registration_open(config: &Config) -> Result<(), InternalError<DbErr>> {
+    if config.registration_mode == RegistrationMode::Closed {
+        Err(InternalError::new(
+            DbErr::Custom("registration is closed".to_string()),
+            StatusCode::FORBIDDEN,
+        ))
     } else {
-        HttpResponse::build(StatusCode::OK).json(LoginResponse {
-            status: false,
-            message: "invalid login info",
-        })
+        Ok(())
+    }
+}
+
+// Checks password registration and login are enabled, they can be turned off for SSO
+// If they are off, fails with 403 Forbidden
+pub fn check_password_login(config: &Config) -> Result<(), InternalError<DbErr>> {
+    if config.password_login {
+        Ok(())
+    } else {
+        Err(InternalError::new(
+            DbErr::Custom("password login is disabled, use single sign-on".to_string()),
+            StatusCode::FORBIDDEN,
+        ))
+    }
+}
+
+// POST /logout
+// Takes in user auth
+// On success, deletes the current token and returns 200 OK with JSON encoded LoginResponse
+// and a removal cookie
+// If the token is a personal access token, returns 403 Forbidden
+// On error, returns 500 Internal Server Error
+pub async fn logout(
+    req: HttpRequest,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<HttpResponse, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let user_id = token.user_id;
+
+    token
+        .delete(db.get_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    audit::record(
+        db.get_ref(),
+        &req,
+        audit_event::LOGOUT,
+        Some(user_id),
+        None,
+        None,
+    )
+    .await;
+
+    Ok(logged_out(&config))
+}
+
+// POST /logout/all
+// Takes in user auth
+// On success, deletes every token of the user and returns 200 OK with JSON encoded
+// LoginResponse and a removal cookie
+// On error, returns 500 Internal Server Error
+pub async fn logout_all(
+    req: HttpRequest,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<HttpResponse, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    token::Entity::delete_many()
+        .filter(token::Column::UserId.eq(token.user_id))
+        .exec(db.get_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    audit::record(
+        db.get_ref(),
+        &req,
+        audit_event::LOGOUT_ALL,
+        Some(token.user_id),
+        None,
+        None,
+    )
+    .await;
+
+    Ok(logged_out(&config))
+}
+
+// Builds the logout response, telling the browser to drop the token cookie
+fn logged_out(config: &Config) -> HttpResponse {
+    let mut response = HttpResponse::build(StatusCode::OK).json(LoginResponse {
+        status: true,
+        message: "logout successful",
+    });
+
+    let cookie = Cookie::build("token", "")
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(config.cookie_same_site)
+        .finish();
+    response.add_removal_cookie(&cookie).unwrap();
+
+    response
+}
+
+// Builds the cookie carrying the token id, kept by the browser for the token lifetime
+// The path is set so a cookie renewed or issued below /login or /oidc still covers the whole API
+fn token_cookie(uuid: Uuid, config: &Config) -> Cookie<'static> {
+    let mut buf = [b'x'; 36];
+    let str = uuid.to_hyphenated().encode_lower(&mut buf);
+
+    Cookie::build("token", str.to_owned())
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .max_age(CookieDuration::seconds(config.token_lifetime.num_seconds()))
+        .same_site(config.cookie_same_site)
+        .finish()
+}
+
+// Marks a request whose token was renewed, holding the cookie to send back
+struct RenewedToken(Cookie<'static>);
+
+// Middleware that sends the renewed token cookie set by the token extractor
+pub async fn renew_token_cookie(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let mut res = next.call(req).await?;
+
+    let renewed = res
+        .request()
+        .extensions()
+        .get::<RenewedToken>()
+        .map(|r| r.0.clone());
+
+    if let Some(cookie) = renewed {
+        res.response_mut().add_cookie(&cookie)?;
     }
+
+    Ok(res)
 }
 
 // Implements user authentication
-// Takes token from cookie and checks against database
+// Reads a Bearer Authorization header, None without one and Some(None) when it doesn't hold
+// a personal access token
+pub fn bearer_token(req: &HttpRequest) -> Option<Option<Uuid>> {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| token::parse_access_token(v.trim()))
+}
+
+// Takes a personal access token from the Authorization header, or a session token from
+// the cookie, and checks against database
+// Session tokens close to expiry are renewed, see renew_token_cookie
 impl FromRequest for token::Model {
     type Error = InternalError<&'static str>;
 
@@ -134,13 +644,17 @@ impl FromRequest for token::Model {
     >;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
-        let uuid = match req
-            .cookie("token")
-            .as_ref()
-            .map(Cookie::value)
-            .map(Uuid::parse_str)
-        {
-            Some(Ok(t)) => t,
+        let bearer = bearer_token(req);
+
+        let is_access_token = bearer.is_some();
+
+        let uuid = match bearer.or_else(|| {
+            req.cookie("token")
+                .as_ref()
+                .map(Cookie::value)
+                .map(|v| Uuid::parse_str(v).ok())
+        }) {
+            Some(Some(t)) => t,
             _ => {
                 return future::err(InternalError::new(
                     "no valid token",
@@ -156,22 +670,166 @@ impl FromRequest for token::Model {
             .as_ref()
             .clone();
 
-        let hash = hash_with_salt(uuid.as_bytes(), 4, &[0; 16][..])
-            .unwrap()
-            .to_string();
+        let config = req.app_data::<Data<Config>>().unwrap().clone();
+
+        let hash = token_digest(&config.token_secret, uuid.as_bytes());
+
+        let req = req.clone();
 
         async move {
-            Ok(match token::Entity::find_by_id(hash).one(&db).await {
-                Ok(Some(t)) if t.expires_at > chrono::Utc::now().naive_utc() => t,
-                _ => {
+            let now = chrono::Utc::now().naive_utc();
+
+            // Unknown tokens aren't recorded, so anonymous clients can't fill the audit log,
+            // and a user's rejections are recorded at most once a minute per reason
+            let rejected = |user_id: Option<i64>, reason: &'static str, status: StatusCode| {
+                let db = db.clone();
+                let req = req.clone();
+                async move {
+                    if let Some(user_id) = user_id {
+                        let recent = audit_event::Entity::find()
+                            .filter(audit_event::Column::Event.eq(audit_event::TOKEN_REJECTED))
+                            .filter(audit_event::Column::UserId.eq(user_id))
+                            .filter(audit_event::Column::Detail.eq(reason))
+                            .filter(
+                                audit_event::Column::CreatedAt
+                                    .gt(now - chrono::Duration::minutes(1)),
+                            )
+                            .one(&db)
+                            .await;
+
+                        if let Ok(None) = recent {
+                            audit::record(
+                                &db,
+                                &req,
+                                audit_event::TOKEN_REJECTED,
+                                Some(user_id),
+                                None,
+                                Some(reason),
+                            )
+                            .await;
+                        }
+                    }
+                    InternalError::new(reason, status)
+                }
+            };
+
+            let mut found = token::Entity::find()
+                .filter(token::Column::Hash.eq(hash.as_str()))
+                .one(&db)
+                .await;
+
+            // Fall back to the old bcrypt digest, upgrading the row when found
+            if let (Ok(None), true) = (&found, config.legacy_token_hashes) {
+                found = token::Entity::find()
+                    .filter(token::Column::Hash.eq(legacy_token_digest(uuid.as_bytes())))
+                    .one(&db)
+                    .await;
+
+                if let Ok(Some(t)) = &mut found {
+                    let _ = token::Entity::update_many()
+                        .col_expr(token::Column::Hash, Expr::value(hash.as_str()))
+                        .filter(token::Column::Id.eq(t.id))
+                        .exec(&db)
+                        .await;
+                    t.hash = hash;
+                }
+            }
+
+            // Access tokens only work as bearer tokens, session tokens only as cookies
+            let mut token = match found {
+                Ok(Some(t)) if t.expires_at > now && t.is_access_token() == is_access_token => t,
+                Ok(t) => {
+                    return Err(rejected(
+                        t.map(|t| t.user_id),
+                        "invalid token",
+                        StatusCode::UNAUTHORIZED,
+                    )
+                    .await)
+                }
+                Err(_) => {
                     return Err(InternalError::new(
                         "invalid token",
                         StatusCode::UNAUTHORIZED,
                     ))
                 }
-            })
+            };
+
+            // Tokens stop working while their account can't be used
+            match user::Entity::find_by_id(token.user_id).one(&db).await {
+                Ok(Some(user)) => {
+                    if let Some(message) = user.status_error(now) {
+                        return Err(rejected(Some(user.id), message, StatusCode::FORBIDDEN).await);
+                    }
+                }
+                _ => {
+                    return Err(rejected(
+                        Some(token.user_id),
+                        "invalid token",
+                        StatusCode::UNAUTHORIZED,
+                    )
+                    .await)
+                }
+            }
+
+            if let (Some(idle_timeout), false) = (config.token_idle_timeout, is_access_token) {
+                if now - token.last_used_at > idle_timeout {
+                    return Err(rejected(
+                        Some(token.user_id),
+                        "token expired from inactivity",
+                        StatusCode::UNAUTHORIZED,
+                    )
+                    .await);
+                }
+            }
+
+            // Slide the expiry forward when the session is about to run out
+            if !is_access_token && token.expires_at - now < config.token_renew_window {
+                let expiry = now + config.token_lifetime;
+
+                let _ = token::Entity::update_many()
+                    .col_expr(token::Column::ExpiresAt, Expr::value(expiry))
+                    .filter(token::Column::Id.eq(token.id))
+                    .exec(&db)
+                    .await;
+                token.expires_at = expiry;
+
+                req.extensions_mut()
+                    .insert(RenewedToken(token_cookie(uuid, &config)));
+            }
+
+            // Only touch the row once a minute to avoid a write on every request
+            if now - token.last_used_at > chrono::Duration::minutes(1) {
+                let _ = token::Entity::update_many()
+                    .col_expr(token::Column::LastUsedAt, Expr::value(now))
+                    .filter(token::Column::Id.eq(token.id))
+                    .exec(&db)
+                    .await;
+                token.last_used_at = now;
+            }
+
+            Ok(token)
         }
         .boxed_local()
         .right_future()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(mode: RegistrationMode) -> Config {
+        let mut config = Config::from_env();
+        config.registration_mode = mode;
+        config
+    }
+
+    #[test]
+    fn registration_open_unless_closed() {
+        assert!(check_registration_open(&config(RegistrationMode::Open)).is_ok());
+        assert!(check_registration_open(&config(RegistrationMode::InviteOnly)).is_ok());
+
+        let closed = check_registration_open(&config(RegistrationMode::Closed)).unwrap_err();
+        assert_eq!(closed.status_code(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,377 @@
+use actix_web::{
+    error::InternalError,
+    http::StatusCode,
+    web::{Data, Json, Path, Query},
+    HttpResponse,
+};
+use chrono::Utc;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, NotSet,
+    QueryFilter, QueryOrder, QuerySelect, Select, Set,
+};
+
+use crate::{
+    config::Config,
+    model::{
+        board,
+        page::{Page, PageQuery},
+        post,
+        token::{self, Scope},
+    },
+};
+
+use super::{
+    permission::{actor, check_admin, check_scope, check_session},
+    route_post::list_posts,
+    to_bad_request, to_internal_error, to_not_found, to_ok,
+};
+
+const MAX_SLUG_LEN: usize = 32;
+const MAX_NAME_LEN: usize = 64;
+const MAX_DESCRIPTION_LEN: usize = 500;
+
+// Selects board Outputs, with post counts
+fn outputs() -> Select<board::Entity> {
+    board::Entity::find()
+        .column_as(
+            Expr::cust("(SELECT COUNT(*) FROM posts WHERE posts.board_id = boards.id)"),
+            "post_count",
+        )
+        .order_by_asc(board::Column::Position)
+        .order_by_asc(board::Column::Name)
+}
+
+async fn find(db: &DatabaseConnection, slug: &str) -> Result<board::Model, InternalError<DbErr>> {
+    board::Entity::find()
+        .filter(board::Column::Slug.eq(slug))
+        .one(db)
+        .await
+        .transpose()
+        .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+        .and_then(std::convert::identity)
+        .map_err(to_not_found)
+}
+
+fn invalid(message: String) -> InternalError<DbErr> {
+    to_bad_request(DbErr::Custom(message))
+}
+
+// Lowercases a slug, failing with 400 Bad Request unless it is letters, digits and dashes
+fn slug(value: &str) -> Result<String, InternalError<DbErr>> {
+    let value = value.trim().to_lowercase();
+    let valid = (1..=MAX_SLUG_LEN).contains(&value.len())
+        && value
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !value.starts_with('-')
+        && !value.ends_with('-');
+
+    if valid {
+        Ok(value)
+    } else {
+        Err(invalid(format!(
+            "slug must be up to {} lowercase letters, digits and inner dashes",
+            MAX_SLUG_LEN
+        )))
+    }
+}
+
+fn name(value: &str) -> Result<String, InternalError<DbErr>> {
+    let value = value.trim();
+    if value.is_empty() || value.chars().count() > MAX_NAME_LEN {
+        return Err(invalid(format!(
+            "name must be 1 to {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+    Ok(value.to_owned())
+}
+
+// Trims the description, empty ones become None
+fn description(value: &str) -> Result<Option<String>, InternalError<DbErr>> {
+    let value = value.trim();
+    if value.chars().count() > MAX_DESCRIPTION_LEN {
+        return Err(invalid(format!(
+            "description is longer than {} characters",
+            MAX_DESCRIPTION_LEN
+        )));
+    }
+    Ok(Some(value.to_owned()).filter(|v| !v.is_empty()))
+}
+
+async fn slug_taken(db: &DatabaseConnection, slug: &str) -> Result<(), InternalError<DbErr>> {
+    let taken = board::Entity::find()
+        .filter(board::Column::Slug.eq(slug))
+        .one(db)
+        .await
+        .map_err(to_internal_error)?
+        .is_some();
+
+    if taken {
+        Err(InternalError::new(
+            DbErr::Custom(format!("board {} already exists", slug)),
+            StatusCode::CONFLICT,
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// Finds the parent board with the slug, checking it isn't the board itself or below it
+// If the parent does not exist or would make a cycle, fails with 400 Bad Request
+async fn parent(
+    db: &DatabaseConnection,
+    slug: &str,
+    board_id: Option<i64>,
+) -> Result<board::Model, InternalError<DbErr>> {
+    let parent = board::Entity::find()
+        .filter(board::Column::Slug.eq(slug))
+        .one(db)
+        .await
+        .map_err(to_internal_error)?
+        .ok_or_else(|| invalid(format!("no board {}", slug)))?;
+
+    let mut ancestor = Some(parent.clone());
+    while let Some(board) = ancestor {
+        if Some(board.id) == board_id {
+            return Err(invalid(String::from(
+                "a board can't be placed below itself",
+            )));
+        }
+        ancestor = match board.parent_id {
+            Some(id) => board::Entity::find_by_id(id)
+                .one(db)
+                .await
+                .map_err(to_internal_error)?,
+            None => None,
+        };
+    }
+
+    Ok(parent)
+}
+
+// GET /board
+// On success, returns 200 OK with JSON encoded board Outputs, by position then name
+pub async fn read_all(
+    db: Data<DatabaseConnection>,
+) -> Result<Json<Vec<board::Output>>, InternalError<DbErr>> {
+    outputs()
+        .into_model::<board::Output>()
+        .all(db.as_ref())
+        .await
+        .map(Json)
+        .map_err(to_internal_error)
+}
+
+// GET /board/{slug}
+// On success, returns 200 OK with JSON encoded board Output
+// If slug does not exist, returns 404 Not Found
+pub async fn read(
+    param: Path<String>,
+    db: Data<DatabaseConnection>,
+) -> Result<Json<board::Output>, InternalError<DbErr>> {
+    let slug = param.into_inner();
+
+    outputs()
+        .filter(board::Column::Slug.eq(slug))
+        .into_model::<board::Output>()
+        .one(db.as_ref())
+        .await
+        .transpose()
+        .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+        .and_then(std::convert::identity)
+        .map(Json)
+        .map_err(to_not_found)
+}
+
+// GET /board/{slug}/posts
+// Takes in query encoded post ListQuery and page PageQuery, as GET /post/all
+// Optionally takes in user auth, access tokens need the posts:read scope
+// On success, returns 200 OK with a JSON encoded Page of the board's post Outputs
+// If a parameter or the cursor is invalid, returns 400 Bad Request
+// If slug does not exist, returns 404 Not Found
+pub async fn posts(
+    Query(list): Query<post::ListQuery>,
+    Query(page): Query<PageQuery>,
+    param: Path<String>,
+    db: Data<DatabaseConnection>,
+    token: Option<token::Model>,
+) -> Result<Json<Page<post::Output>>, InternalError<DbErr>> {
+    if let Some(token) = &token {
+        check_scope(token, Scope::PostsRead)?;
+    }
+
+    let board = find(db.as_ref(), &param.into_inner()).await?;
+
+    list_posts(
+        db.as_ref(),
+        post::Entity::find().filter(post::Column::BoardId.eq(board.id)),
+        list,
+        page,
+        token.map(|token| token.user_id),
+    )
+    .await
+    .map(Json)
+}
+
+// POST /board
+// Takes in JSON encoded board Input and admin auth
+// On success, returns 200 OK with JSON encoded board Model
+// If a field is invalid or the parent does not exist, returns 400 Bad Request
+// If the caller is not an admin, returns 403 Forbidden
+// If the slug is taken, returns 409 Conflict
+pub async fn create(
+    Json(input): Json<board::Input>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<Json<board::Model>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let actor = actor(db.as_ref(), &token).await?;
+    check_admin(&actor, &config)?;
+
+    let slug = slug(&input.slug)?;
+    let name = name(&input.name)?;
+    let description = description(input.description.as_deref().unwrap_or_default())?;
+    let parent_id = match input.parent.as_deref().map(str::trim) {
+        Some(parent_slug) if !parent_slug.is_empty() => {
+            Some(parent(db.as_ref(), parent_slug, None).await?.id)
+        }
+        _ => None,
+    };
+
+    slug_taken(db.as_ref(), &slug).await?;
+
+    board::ActiveModel {
+        slug: Set(slug),
+        name: Set(name),
+        description: Set(description),
+        position: Set(input.position.unwrap_or(0)),
+        parent_id: Set(parent_id),
+        created_at: Set(Utc::now().naive_utc()),
+        ..Default::default()
+    }
+    .insert(db.as_ref())
+    .await
+    .map(Json)
+    .map_err(to_internal_error)
+}
+
+// PATCH /board/{slug}
+// Takes in JSON encoded board Update and admin auth
+// Omitted fields are left alone, an empty description or parent clears it
+// On success, updates and returns 200 OK with JSON encoded board Model
+// If a field is invalid, the parent does not exist or is below the board, or the
+// default board would be renamed, returns 400 Bad Request
+// If the caller is not an admin, returns 403 Forbidden
+// If slug does not exist, returns 404 Not Found
+// If the new slug is taken, returns 409 Conflict
+pub async fn update(
+    Json(input): Json<board::Update>,
+    param: Path<String>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<Json<board::Model>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let actor = actor(db.as_ref(), &token).await?;
+    check_admin(&actor, &config)?;
+
+    let board = find(db.as_ref(), &param.into_inner()).await?;
+
+    let new_slug = match &input.slug {
+        Some(value) => Some(slug(value)?).filter(|s| *s != board.slug),
+        None => None,
+    };
+    if let Some(new_slug) = &new_slug {
+        if board.slug == config.default_board {
+            return Err(invalid(String::from("the default board can't be renamed")));
+        }
+        slug_taken(db.as_ref(), new_slug).await?;
+    }
+
+    let parent_id = match input.parent.as_deref().map(str::trim) {
+        Some("") => Set(None),
+        Some(parent_slug) => Set(Some(
+            parent(db.as_ref(), parent_slug, Some(board.id)).await?.id,
+        )),
+        None => NotSet,
+    };
+
+    board::ActiveModel {
+        id: Set(board.id),
+        slug: new_slug.map_or(NotSet, Set),
+        name: match &input.name {
+            Some(value) => Set(name(value)?),
+            None => NotSet,
+        },
+        description: match &input.description {
+            Some(value) => Set(description(value)?),
+            None => NotSet,
+        },
+        position: input.position.map_or(NotSet, Set),
+        parent_id,
+        ..Default::default()
+    }
+    .update(db.as_ref())
+    .await
+    .map(Json)
+    .map_err(to_internal_error)
+}
+
+// DELETE /board/{slug}
+// Takes in admin auth
+// On success, deletes and returns 200 OK
+// If the caller is not an admin, returns 403 Forbidden
+// If slug does not exist, returns 404 Not Found
+// If the board is the default board, or still has posts or boards below it, returns
+// 409 Conflict
+pub async fn delete(
+    param: Path<String>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<HttpResponse, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let actor = actor(db.as_ref(), &token).await?;
+    check_admin(&actor, &config)?;
+
+    let board = find(db.as_ref(), &param.into_inner()).await?;
+
+    let conflict = |message: &str| {
+        InternalError::new(DbErr::Custom(message.to_string()), StatusCode::CONFLICT)
+    };
+
+    if board.slug == config.default_board {
+        return Err(conflict("the default board can't be deleted"));
+    }
+
+    let has_posts = post::Entity::find()
+        .filter(post::Column::BoardId.eq(board.id))
+        .one(db.as_ref())
+        .await
+        .map_err(to_internal_error)?
+        .is_some();
+    if has_posts {
+        return Err(conflict("board still has posts, move them first"));
+    }
+
+    let has_children = board::Entity::find()
+        .filter(board::Column::ParentId.eq(board.id))
+        .one(db.as_ref())
+        .await
+        .map_err(to_internal_error)?
+        .is_some();
+    if has_children {
+        return Err(conflict("board still has boards below it"));
+    }
+
+    board::ActiveModel::from(board)
+        .delete(db.as_ref())
+        .await
+        .map(to_ok)
+        .map_err(to_internal_error)
+}
@@ -0,0 +1,276 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    error::InternalError,
+    http::{
+        header::{HeaderName, ORIGIN, REFERER},
+        Method, StatusCode,
+    },
+    middleware::Next,
+    web::{Data, Json},
+    HttpRequest,
+};
+use serde::Serialize;
+use url::Url;
+
+use crate::{config::Config, crypto::token_digest};
+
+use super::auth::bearer_token;
+
+// Header carrying the CSRF token on state-changing requests
+pub const CSRF_HEADER: HeaderName = HeaderName::from_static("x-csrf-token");
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CsrfToken {
+    pub token: Option<String>,
+}
+
+// Derives the CSRF token of a session from its cookie, so it changes with every login
+fn csrf_token(config: &Config, session: &str) -> String {
+    token_digest(&config.token_secret, format!("csrf:{}", session).as_bytes())
+}
+
+// GET /csrf
+// Takes in the session cookie, if any
+// Returns 200 OK with JSON encoded CsrfToken to send in the X-CSRF-Token header,
+// the token is null without a session cookie
+pub async fn read(req: HttpRequest, config: Data<Config>) -> Json<CsrfToken> {
+    Json(CsrfToken {
+        token: req
+            .cookie("token")
+            .map(|cookie| csrf_token(&config, cookie.value())),
+    })
+}
+
+// Whether the request comes from an allowed origin or the server's own
+// Browsers send Origin or Referer on cross-site requests, clients sending neither are let through
+fn origin_allowed(req: &HttpRequest, config: &Config) -> bool {
+    let source = match req
+        .headers()
+        .get(ORIGIN)
+        .or_else(|| req.headers().get(REFERER))
+    {
+        Some(source) => source,
+        None => return true,
+    };
+
+    let url = match source.to_str().ok().and_then(|s| Url::parse(s).ok()) {
+        Some(url) => url,
+        None => return false,
+    };
+
+    let same_host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_owned(),
+        (None, _) => return false,
+    } == req.connection_info().host();
+
+    same_host
+        || config
+            .allowed_origins
+            .contains(&url.origin().ascii_serialization())
+}
+
+// Middleware rejecting cross-site requests that change state
+// Requests from other origins are refused, and requests authenticated by the session
+// cookie must carry its CSRF token, see GET /csrf
+// Bearer tokens are never sent by browsers on their own, so requests carrying a well-formed
+// personal access token need no CSRF token, any other Authorization header doesn't count
+pub async fn protect(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.call(req).await;
+    }
+
+    let config = req.app_data::<Data<Config>>().unwrap().clone();
+
+    if !origin_allowed(req.request(), &config) {
+        return Err(InternalError::new("origin not allowed", StatusCode::FORBIDDEN).into());
+    }
+
+    if let (Some(session), false) = (
+        req.cookie("token"),
+        matches!(bearer_token(req.request()), Some(Some(_))),
+    ) {
+        let sent = req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok());
+
+        if sent != Some(csrf_token(&config, session.value()).as_str()) {
+            return Err(
+                InternalError::new("missing or invalid CSRF token", StatusCode::FORBIDDEN).into(),
+            );
+        }
+    }
+
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::header::{AUTHORIZATION, HOST},
+        middleware::from_fn,
+        test::{init_service, try_call_service, TestRequest},
+        web, App, HttpResponse,
+    };
+    use sea_orm::prelude::Uuid;
+
+    use super::*;
+    use crate::model::token::format_access_token;
+
+    const SESSION: &str = "6a1f0c3e-8d1b-4f3a-9c1e-2b7d5e4f6a8b";
+
+    fn config() -> Config {
+        let mut config = Config::from_env();
+        config.token_secret = b"secret".to_vec();
+        config.allowed_origins = vec![String::from("http://127.0.0.1:8080")];
+        config
+    }
+
+    #[test]
+    fn csrf_token_is_stable_per_session() {
+        let config = config();
+        assert_eq!(csrf_token(&config, SESSION), csrf_token(&config, SESSION));
+        assert_ne!(csrf_token(&config, SESSION), csrf_token(&config, "other"));
+        // Not the digest the session itself is stored under
+        assert_ne!(
+            csrf_token(&config, SESSION),
+            token_digest(&config.token_secret, SESSION.as_bytes())
+        );
+    }
+
+    #[test]
+    fn csrf_token_depends_on_the_secret() {
+        let config = config();
+        let mut rotated = config.clone();
+        rotated.token_secret = b"rotated".to_vec();
+        assert_ne!(csrf_token(&config, SESSION), csrf_token(&rotated, SESSION));
+    }
+
+    fn request(header: Option<(HeaderName, &str)>) -> HttpRequest {
+        let mut req = TestRequest::post().insert_header((HOST, "127.0.0.1:8000"));
+        if let Some(header) = header {
+            req = req.insert_header(header);
+        }
+        req.to_http_request()
+    }
+
+    #[test]
+    fn origin_allowed_for_own_and_allowed_origins() {
+        let config = config();
+        assert!(origin_allowed(&request(None), &config));
+        assert!(origin_allowed(
+            &request(Some((ORIGIN, "http://127.0.0.1:8000"))),
+            &config
+        ));
+        assert!(origin_allowed(
+            &request(Some((ORIGIN, "http://127.0.0.1:8080"))),
+            &config
+        ));
+        assert!(origin_allowed(
+            &request(Some((REFERER, "http://127.0.0.1:8080/post/1"))),
+            &config
+        ));
+    }
+
+    #[test]
+    fn origin_refused_for_other_origins() {
+        let config = config();
+        for (header, value) in [
+            (ORIGIN, "https://evil.example"),
+            // Same host, different port
+            (ORIGIN, "http://127.0.0.1:9000"),
+            // Allowed origins match scheme too
+            (ORIGIN, "https://127.0.0.1:8080"),
+            (ORIGIN, "null"),
+            (REFERER, "https://evil.example/?http://127.0.0.1:8080"),
+        ] {
+            assert!(
+                !origin_allowed(&request(Some((header, value))), &config),
+                "{}",
+                value
+            );
+        }
+    }
+
+    async fn status(req: TestRequest) -> StatusCode {
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(config()))
+                .wrap(from_fn(protect))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // Refusals come back as errors, the response is built from them
+        match try_call_service(
+            &app,
+            req.insert_header((HOST, "127.0.0.1:8000")).to_request(),
+        )
+        .await
+        {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    fn with_session(req: TestRequest) -> TestRequest {
+        req.cookie(actix_web::cookie::Cookie::new("token", SESSION))
+    }
+
+    #[actix_web::test]
+    async fn protect_lets_safe_methods_through() {
+        let req = with_session(TestRequest::get()).insert_header((ORIGIN, "https://evil.example"));
+        assert_eq!(status(req).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn protect_wants_the_session_csrf_token() {
+        assert_eq!(
+            status(with_session(TestRequest::post())).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(with_session(TestRequest::post()).insert_header((CSRF_HEADER, "wrong"))).await,
+            StatusCode::FORBIDDEN
+        );
+
+        let other = csrf_token(&config(), "other");
+        assert_eq!(
+            status(with_session(TestRequest::post()).insert_header((CSRF_HEADER, other))).await,
+            StatusCode::FORBIDDEN
+        );
+
+        let token = csrf_token(&config(), SESSION);
+        assert_eq!(
+            status(with_session(TestRequest::post()).insert_header((CSRF_HEADER, token))).await,
+            StatusCode::OK
+        );
+    }
+
+    #[actix_web::test]
+    async fn protect_refuses_other_origins_even_with_the_token() {
+        let req = with_session(TestRequest::post())
+            .insert_header((CSRF_HEADER, csrf_token(&config(), SESSION)))
+            .insert_header((ORIGIN, "https://evil.example"));
+        assert_eq!(status(req).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn protect_lets_requests_without_a_session_through() {
+        assert_eq!(status(TestRequest::post()).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn protect_exempts_only_well_formed_access_tokens() {
+        let pat = format!("Bearer {}", format_access_token(Uuid::new_v4()));
+        let req = with_session(TestRequest::post()).insert_header((AUTHORIZATION, pat));
+        assert_eq!(status(req).await, StatusCode::OK);
+
+        for header in ["Bearer nonsense", "Basic dXNlcjpwYXNz"] {
+            let req = with_session(TestRequest::post()).insert_header((AUTHORIZATION, header));
+            assert_eq!(status(req).await, StatusCode::FORBIDDEN, "{}", header);
+        }
+    }
+}
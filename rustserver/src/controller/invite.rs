@@ -0,0 +1,227 @@
+use actix_web::{
+    error::InternalError,
+    web::{Data, Json, Path},
+    HttpResponse,
+};
+use chrono::{Duration, Utc};
+use sea_orm::{
+    prelude::Uuid,
+    sea_query::{Expr, IntoCondition},
+    ActiveModelTrait, ColumnTrait, Condition, ConnectionTrait, DatabaseConnection, DbErr,
+    EntityTrait, QueryFilter, QueryOrder, Set,
+};
+
+use crate::{
+    config::Config,
+    crypto::code_digest,
+    model::{
+        invite, token,
+        user::{self, FieldError, Role},
+    },
+};
+
+use super::{
+    permission::{actor, check_admin, check_session},
+    to_bad_request, to_internal_error, to_not_found, to_ok,
+};
+
+// Longest an invite made by an admin may stay valid
+const MAX_ADMIN_DAYS: i64 = 365;
+
+// The registration error for an invite that can't be used
+pub fn unusable() -> FieldError {
+    FieldError {
+        field: "invite",
+        code: "invalid",
+        message: String::from("invite is invalid, expired or used up"),
+    }
+}
+
+// Invites that have not expired and have uses left
+fn usable() -> Condition {
+    Condition::all()
+        .add(
+            Condition::any()
+                .add(invite::Column::ExpiresAt.is_null())
+                .add(invite::Column::ExpiresAt.gt(Utc::now().naive_utc())),
+        )
+        .add(
+            Condition::any()
+                .add(invite::Column::MaxUses.is_null())
+                .add(Expr::cust("uses < max_uses").into_condition()),
+        )
+}
+
+// Finds the invite with the code, if it can still be used
+// Invites stop working when whoever made them can no longer use their account
+pub async fn find_usable(
+    db: &DatabaseConnection,
+    code: &str,
+) -> Result<Option<invite::Model>, DbErr> {
+    let uuid = match invite::parse_invite(code) {
+        Some(uuid) => uuid,
+        None => return Ok(None),
+    };
+
+    let found = invite::Entity::find()
+        .filter(invite::Column::Hash.eq(code_digest(uuid.as_bytes())))
+        .filter(usable())
+        .find_also_related(user::Entity)
+        .one(db)
+        .await?;
+
+    let now = Utc::now().naive_utc();
+    Ok(match found {
+        Some((invite, Some(creator))) if creator.status_error(now).is_none() => Some(invite),
+        _ => None,
+    })
+}
+
+// Uses up one use of the invite, false when it ran out in the meantime
+pub async fn claim<'a, C: ConnectionTrait<'a>>(db: &'a C, invite_id: i64) -> Result<bool, DbErr> {
+    let res = invite::Entity::update_many()
+        .col_expr(invite::Column::Uses, Expr::col(invite::Column::Uses).add(1))
+        .filter(invite::Column::Id.eq(invite_id))
+        .filter(usable())
+        .exec(db)
+        .await?;
+
+    Ok(res.rows_affected == 1)
+}
+
+// POST /me/invites
+// Takes in JSON encoded invite InviteInput and session auth
+// Omitted limits default to the most allowed, only admins may make unlimited invites
+// On success, returns 200 OK with JSON encoded invite CreatedInvite, the only time the
+// code is returned
+// If a limit is out of range, returns 400 Bad Request
+pub async fn create(
+    Json(input): Json<invite::InviteInput>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<Json<invite::CreatedInvite>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let actor = actor(db.as_ref(), &token).await?;
+
+    let (max_uses, max_days) = if actor.role == Role::Admin {
+        (input.max_uses, input.expires_in_days)
+    } else {
+        (
+            Some(input.max_uses.unwrap_or(config.invite_max_uses)),
+            Some(input.expires_in_days.unwrap_or(config.invite_max_days)),
+        )
+    };
+
+    let (uses_limit, days_limit) = if actor.role == Role::Admin {
+        (i32::MAX, MAX_ADMIN_DAYS)
+    } else {
+        (config.invite_max_uses, config.invite_max_days)
+    };
+
+    if max_uses.is_some_and(|uses| !(1..=uses_limit).contains(&uses)) {
+        return Err(to_bad_request(DbErr::Custom(format!(
+            "max_uses must be 1 to {}",
+            uses_limit
+        ))));
+    }
+    if max_days.is_some_and(|days| !(1..=days_limit).contains(&days)) {
+        return Err(to_bad_request(DbErr::Custom(format!(
+            "expires_in_days must be 1 to {}",
+            days_limit
+        ))));
+    }
+
+    let uuid = Uuid::new_v4();
+    let now = Utc::now();
+
+    let invite = invite::ActiveModel {
+        hash: Set(code_digest(uuid.as_bytes())),
+        created_by: Set(actor.id),
+        max_uses: Set(max_uses),
+        uses: Set(0),
+        expires_at: Set(max_days.map(|days| (now + Duration::days(days)).naive_utc())),
+        created_at: Set(now.naive_utc()),
+        ..Default::default()
+    }
+    .insert(db.as_ref())
+    .await
+    .map_err(to_internal_error)?;
+
+    Ok(Json(invite::CreatedInvite {
+        invite,
+        code: invite::format_invite(uuid),
+    }))
+}
+
+// GET /me/invites
+// Takes in session auth
+// On success, returns 200 OK with JSON encoded invites made by the user, newest first
+pub async fn read_all(
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<Json<Vec<invite::Model>>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    invite::Entity::find()
+        .filter(invite::Column::CreatedBy.eq(token.user_id))
+        .order_by_desc(invite::Column::Id)
+        .all(db.as_ref())
+        .await
+        .map(Json)
+        .map_err(to_internal_error)
+}
+
+// DELETE /me/invites/{invite_id}
+// Takes in session auth
+// On success, expires the invite and returns 200 OK, it is kept for tracing
+// If invite_id does not exist for the user, returns 404 Not Found
+pub async fn delete(
+    param: Path<i64>,
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<HttpResponse, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let invite_id = param.into_inner();
+
+    let res = invite::Entity::update_many()
+        .col_expr(
+            invite::Column::ExpiresAt,
+            Expr::value(Utc::now().naive_utc()),
+        )
+        .filter(invite::Column::Id.eq(invite_id))
+        .filter(invite::Column::CreatedBy.eq(token.user_id))
+        .exec(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    if res.rows_affected == 0 {
+        return Err(to_not_found(DbErr::RecordNotFound(String::new())));
+    }
+
+    Ok(to_ok(res))
+}
+
+// GET /invites
+// Takes in admin auth
+// On success, returns 200 OK with JSON encoded invites of every user, newest first
+// If the caller is not an admin, returns 403 Forbidden
+pub async fn read_every(
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<Json<Vec<invite::Model>>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let actor = actor(db.as_ref(), &token).await?;
+    check_admin(&actor, &config)?;
+
+    invite::Entity::find()
+        .order_by_desc(invite::Column::Id)
+        .all(db.as_ref())
+        .await
+        .map(Json)
+        .map_err(to_internal_error)
+}
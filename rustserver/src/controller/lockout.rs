@@ -0,0 +1,160 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, ConnectionTrait, DatabaseConnection, DbBackend, DbErr,
+    EntityTrait, QueryFilter, Statement,
+};
+
+use crate::{config::Config, model::login_attempt};
+
+// Keys login failures are counted under, with the failures each key tolerates
+pub fn keys(config: &Config, username: &str, ip: Option<&str>) -> Vec<(String, i32)> {
+    let mut keys = vec![(login_attempt::user_key(username), config.login_max_failures)];
+    if let Some(ip) = ip {
+        keys.push((login_attempt::ip_key(ip), config.login_max_failures_per_ip));
+    }
+    keys
+}
+
+// Returns when the latest lockout on any of the keys ends, if one is in force
+pub async fn locked_until(
+    db: &DatabaseConnection,
+    keys: &[(String, i32)],
+) -> Result<Option<NaiveDateTime>, DbErr> {
+    let now = Utc::now().naive_utc();
+
+    Ok(login_attempt::Entity::find()
+        .filter(login_attempt::Column::Key.is_in(keys.iter().map(|(k, _)| k.as_str())))
+        .filter(login_attempt::Column::LockedUntil.gt(now))
+        .all(db)
+        .await?
+        .into_iter()
+        .filter_map(|a| a.locked_until)
+        .max())
+}
+
+// How long a key is locked out after its latest failure, None while it has failures to spare
+// Starts at the configured lockout once max_failures is reached and doubles with every
+// further failure, up to the configured cap
+fn lockout_for(config: &Config, failures: i32, max_failures: i32) -> Option<Duration> {
+    if failures < max_failures {
+        return None;
+    }
+
+    let doublings = (failures - max_failures).min(16) as u32;
+    Some((config.login_lockout * 2i32.pow(doublings)).min(config.login_max_lockout))
+}
+
+// Counts a failed login against every key, locking keys out with exponential backoff
+// Failures older than the failure window are forgotten
+pub async fn record_failure(
+    db: &DatabaseConnection,
+    config: &Config,
+    keys: &[(String, i32)],
+) -> Result<(), DbErr> {
+    let now = Utc::now().naive_utc();
+    let window_start = now - config.login_failure_window;
+
+    for (key, max_failures) in keys {
+        let failures: i32 = match db
+            .query_one(Statement::from_sql_and_values(
+                DbBackend::Postgres,
+                r#"INSERT INTO login_attempts (key, failures, last_failure_at)
+                VALUES ($1, 1, $2)
+                ON CONFLICT (key) DO UPDATE SET
+                    failures = CASE
+                        WHEN login_attempts.last_failure_at < $3 THEN 1
+                        ELSE login_attempts.failures + 1
+                    END,
+                    last_failure_at = $2
+                RETURNING failures"#,
+                vec![key.as_str().into(), now.into(), window_start.into()],
+            ))
+            .await?
+        {
+            Some(row) => row.try_get("", "failures")?,
+            None => continue,
+        };
+
+        if let Some(lockout) = lockout_for(config, failures, *max_failures) {
+            login_attempt::Entity::update_many()
+                .col_expr(
+                    login_attempt::Column::LockedUntil,
+                    Expr::value(Some(now + lockout)),
+                )
+                .filter(login_attempt::Column::Key.eq(key.as_str()))
+                .exec(db)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Forgets the failures of a username after it logs in
+pub async fn record_success(db: &DatabaseConnection, username: &str) -> Result<(), DbErr> {
+    login_attempt::Entity::delete_many()
+        .filter(login_attempt::Column::Key.eq(login_attempt::user_key(username)))
+        .exec(db)
+        .await
+        .map(|_| ())
+}
+
+// Seconds until a lockout ends, for the Retry-After header
+pub fn retry_after(locked_until: NaiveDateTime) -> i64 {
+    (locked_until - Utc::now().naive_utc())
+        .max(Duration::seconds(1))
+        .num_seconds()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        let mut config = Config::from_env();
+        config.login_max_failures = 5;
+        config.login_max_failures_per_ip = 20;
+        config.login_lockout = Duration::seconds(30);
+        config.login_max_lockout = Duration::seconds(3600);
+        config
+    }
+
+    #[test]
+    fn lockout_for_waits_for_max_failures() {
+        let config = config();
+        assert_eq!(lockout_for(&config, 1, 5), None);
+        assert_eq!(lockout_for(&config, 4, 5), None);
+        assert_eq!(lockout_for(&config, 5, 5), Some(Duration::seconds(30)));
+    }
+
+    #[test]
+    fn lockout_for_doubles_up_to_the_cap() {
+        let config = config();
+        assert_eq!(lockout_for(&config, 6, 5), Some(Duration::seconds(60)));
+        assert_eq!(lockout_for(&config, 7, 5), Some(Duration::seconds(120)));
+        assert_eq!(lockout_for(&config, 11, 5), Some(Duration::seconds(1920)));
+        assert_eq!(lockout_for(&config, 12, 5), Some(Duration::seconds(3600)));
+        // Far past the cap the doubling must not overflow
+        assert_eq!(
+            lockout_for(&config, i32::MAX, 5),
+            Some(Duration::seconds(3600))
+        );
+    }
+
+    #[test]
+    fn keys_add_the_ip_only_when_known() {
+        let config = config();
+
+        assert_eq!(
+            keys(&config, "Alice", None),
+            vec![(login_attempt::user_key("alice"), 5)]
+        );
+        assert_eq!(
+            keys(&config, "Alice", Some("10.0.0.1")),
+            vec![
+                (login_attempt::user_key("Alice"), 5),
+                (login_attempt::ip_key("10.0.0.1"), 20),
+            ]
+        );
+    }
+}
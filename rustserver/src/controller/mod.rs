@@ -1,31 +1,110 @@
+// Handlers and their helpers all fail with InternalError<DbErr>
+#![allow(clippy::result_large_err)]
+
+mod access_token;
+mod audit;
 mod auth;
+mod board;
+mod csrf;
+mod invite;
+mod lockout;
+mod oidc;
+mod password;
+mod permission;
 mod post;
 mod reply;
+mod search;
+mod session;
+mod tag;
+mod two_factor;
+mod user;
+mod validation;
+mod verification;
+mod vote;
 
 use actix_web::{
     error::InternalError,
-    http::StatusCode,
+    http::{header::USER_AGENT, StatusCode},
     web::{self, ServiceConfig},
-    HttpResponse,
+    HttpRequest, HttpResponse,
 };
 
+use argon2::Params;
 use sea_orm::DbErr;
 
-use self::{post as route_post, reply as route_reply};
+use crate::{
+    crypto::{hash_password, verify_password},
+    model::page::{Cursor, PageQuery},
+};
+
+use self::{post as route_post, reply as route_reply, user as route_user};
+
+pub use self::{
+    auth::renew_token_cookie,
+    csrf::{protect as csrf_protect, CSRF_HEADER},
+};
 
 // Helper functions for returning status codes
 fn to_internal_error(e: DbErr) -> InternalError<DbErr> {
     InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR)
 }
 
+fn to_bad_request(e: DbErr) -> InternalError<DbErr> {
+    InternalError::new(e, StatusCode::BAD_REQUEST)
+}
+
 fn to_not_found(e: DbErr) -> InternalError<DbErr> {
     InternalError::new(e, StatusCode::NOT_FOUND)
 }
 
+fn invalid_cursor() -> InternalError<DbErr> {
+    to_bad_request(DbErr::Custom("invalid cursor".to_string()))
+}
+
+// Reads the cursor of a page query, a malformed one fails with 400 Bad Request
+fn page_cursor(page: &PageQuery) -> Result<Option<Cursor>, InternalError<DbErr>> {
+    page.cursor
+        .as_deref()
+        .map(|cursor| Cursor::decode(cursor).ok_or_else(invalid_cursor))
+        .transpose()
+}
+
 fn to_ok<T>(_: T) -> HttpResponse {
     HttpResponse::new(StatusCode::OK)
 }
 
+// Helper functions for describing the client of a request
+fn client_ip(req: &HttpRequest) -> Option<String> {
+    req.peer_addr().map(|addr| addr.ip().to_string())
+}
+
+fn user_agent(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.chars().take(512).collect())
+}
+
+// Password hashing runs on the blocking thread pool, it would stall an async worker
+async fn hash_blocking(password: &str, params: &Params) -> Result<String, InternalError<DbErr>> {
+    let password = password.to_owned();
+    let params = params.clone();
+
+    web::block(move || hash_password(&password, &params))
+        .await
+        .map_err(|e| to_internal_error(DbErr::Custom(e.to_string())))?
+        .map_err(|e| to_internal_error(DbErr::Custom(e.to_string())))
+}
+
+async fn verify_blocking(password: &str, hash: &str) -> Result<bool, InternalError<DbErr>> {
+    let password = password.to_owned();
+    let hash = hash.to_owned();
+
+    web::block(move || verify_password(&password, &hash))
+        .await
+        .map_err(|e| to_internal_error(DbErr::Custom(e.to_string())))
+}
+
 // Configure API routes
 pub fn config(cfg: &mut ServiceConfig) {
     cfg.service(
@@ -37,6 +116,8 @@ pub fn config(cfg: &mut ServiceConfig) {
                     .route("", web::get().to(route_post::read))
                     .route("", web::patch().to(route_post::update))
                     .route("", web::delete().to(route_post::delete))
+                    .route("/vote", web::put().to(vote::cast_post))
+                    .route("/vote", web::delete().to(vote::retract_post))
                     .service(
                         web::scope("/reply")
                             .route("", web::post().to(route_reply::create))
@@ -45,11 +126,92 @@ pub fn config(cfg: &mut ServiceConfig) {
                                 web::scope("/{reply_id}")
                                     .route("", web::get().to(route_reply::read))
                                     .route("", web::patch().to(route_reply::update))
-                                    .route("", web::delete().to(route_reply::delete)),
+                                    .route("", web::delete().to(route_reply::delete))
+                                    .route("/vote", web::put().to(vote::cast_reply))
+                                    .route("/vote", web::delete().to(vote::retract_reply)),
                             ),
                     ),
             ),
     )
+    .service(
+        web::scope("/board")
+            .route("", web::get().to(board::read_all))
+            .route("", web::post().to(board::create))
+            .service(
+                web::scope("/{slug}")
+                    .route("", web::get().to(board::read))
+                    .route("", web::patch().to(board::update))
+                    .route("", web::delete().to(board::delete))
+                    .route("/posts", web::get().to(board::posts)),
+            ),
+    )
+    .service(web::resource("/tags").route(web::get().to(tag::read_all)))
+    .service(web::resource("/tag/{name}/posts").route(web::get().to(tag::posts)))
+    .service(web::resource("/search").route(web::get().to(search::search)))
+    .service(web::resource("/csrf").route(web::get().to(csrf::read)))
     .service(web::resource("/register").route(web::post().to(auth::create)))
-    .service(web::resource("/login").route(web::post().to(auth::login)));
+    .service(
+        web::scope("/login")
+            .route("", web::post().to(auth::login))
+            .route("/2fa", web::post().to(two_factor::login)),
+    )
+    .service(
+        web::scope("/me")
+            .route("", web::get().to(route_user::me))
+            .route("", web::patch().to(route_user::update))
+            .route("/password", web::post().to(password::change))
+            .service(
+                web::scope("/2fa")
+                    .route("", web::post().to(two_factor::enrol))
+                    .route("", web::delete().to(two_factor::disable))
+                    .route("/confirm", web::post().to(two_factor::confirm)),
+            )
+            .service(
+                web::scope("/tokens")
+                    .route("", web::post().to(access_token::create))
+                    .route("", web::get().to(access_token::read_all))
+                    .route("/{token_id}", web::delete().to(access_token::delete)),
+            )
+            .service(
+                web::scope("/invites")
+                    .route("", web::post().to(invite::create))
+                    .route("", web::get().to(invite::read_all))
+                    .route("/{invite_id}", web::delete().to(invite::delete)),
+            ),
+    )
+    .service(
+        web::scope("/oidc")
+            .route("/login", web::get().to(oidc::login))
+            .route("/callback", web::get().to(oidc::callback)),
+    )
+    .service(
+        web::scope("/user/{username}")
+            .route("", web::get().to(route_user::read))
+            .route("/role", web::put().to(route_user::update_role))
+            .route("/status", web::put().to(route_user::update_status))
+            .route("/invitees", web::get().to(route_user::invitees)),
+    )
+    .service(web::resource("/audit").route(web::get().to(audit::read_all)))
+    .service(web::resource("/invites").route(web::get().to(invite::read_every)))
+    .service(web::resource("/moderation/log").route(web::get().to(route_user::moderation_log)))
+    .service(
+        web::scope("/password-reset")
+            .route("/request", web::post().to(password::request_reset))
+            .route("/confirm", web::post().to(password::confirm_reset)),
+    )
+    .service(
+        web::scope("/verify-email")
+            .route("/request", web::post().to(verification::request))
+            .route("/confirm", web::post().to(verification::confirm)),
+    )
+    .service(
+        web::scope("/sessions")
+            .route("", web::get().to(session::read_all))
+            .route("/{session_id}", web::delete().to(session::delete)),
+    )
+    .service(
+        web::scope("/logout")
+            .route("", web::post().to(auth::logout))
+            .route("/all", web::post().to(auth::logout_all)),
+    );
 }
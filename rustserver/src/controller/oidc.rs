@@ -0,0 +1,532 @@
+use actix_web::{
+    cookie::{time::Duration as CookieDuration, Cookie, SameSite},
+    error::InternalError,
+    http::{header::LOCATION, StatusCode},
+    web::{Data, Query},
+    HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use rand::Rng;
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection, DbErr, EntityTrait,
+    QueryFilter, Set,
+};
+use serde::Deserialize;
+
+use crate::{
+    config::{Config, OidcConfig, RegistrationMode},
+    crypto::{generate_urlsafe, pkce_challenge, token_digest, NO_PASSWORD},
+    model::{
+        audit as audit_event, invite as invite_model, token,
+        user::{self, Role, Status},
+    },
+    oidc::{self, Claims},
+};
+
+use super::{
+    audit,
+    auth::{check_registration_open, new_session, username_taken},
+    invite, to_internal_error,
+    two_factor::create_challenge,
+    validation,
+};
+
+// Cookie holding the state, nonce and PKCE verifier of a login in progress
+const FLOW_COOKIE: &str = "oidc_flow";
+// How long the provider's login may take
+const FLOW_MINUTES: i64 = 10;
+
+// Query parameters of GET /oidc/login
+#[derive(Debug, Clone, Deserialize)]
+pub struct Login {
+    invite: Option<String>,
+}
+
+// Query parameters the provider redirects back with
+#[derive(Debug, Clone, Deserialize)]
+pub struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+fn fail(message: String, status: StatusCode) -> InternalError<DbErr> {
+    InternalError::new(DbErr::Custom(message), status)
+}
+
+fn bad_gateway(message: String) -> InternalError<DbErr> {
+    fail(message, StatusCode::BAD_GATEWAY)
+}
+
+fn bad_request(message: &str) -> InternalError<DbErr> {
+    fail(message.to_owned(), StatusCode::BAD_REQUEST)
+}
+
+// Gets the SSO settings, failing with 404 Not Found when SSO is not configured
+fn sso(config: &Config) -> Result<&OidcConfig, InternalError<DbErr>> {
+    config.oidc.as_ref().ok_or_else(|| {
+        fail(
+            String::from("single sign-on is not configured"),
+            StatusCode::NOT_FOUND,
+        )
+    })
+}
+
+// GET /oidc/login
+// Takes an optional invite query parameter, used if the login creates an account
+// Redirects the browser to the identity provider, remembering the flow in a cookie
+// If SSO is not configured, returns 404 Not Found
+// If the invite code is malformed, returns 400 Bad Request
+// If the provider can't be reached, returns 502 Bad Gateway
+pub async fn login(
+    Query(params): Query<Login>,
+    config: Data<Config>,
+) -> Result<HttpResponse, InternalError<DbErr>> {
+    let oidc = sso(&config)?;
+
+    let invite = params
+        .invite
+        .map(|code| code.trim().to_owned())
+        .filter(|code| !code.is_empty());
+    if invite
+        .as_deref()
+        .is_some_and(|code| invite_model::parse_invite(code).is_none())
+    {
+        return Err(bad_request(&invite::unusable().message));
+    }
+
+    let discovery = oidc::discover(oidc).await.map_err(bad_gateway)?;
+
+    let state = generate_urlsafe(16);
+    let nonce = generate_urlsafe(16);
+    let verifier = generate_urlsafe(32);
+
+    let location =
+        oidc::authorization_url(&discovery, oidc, &state, &nonce, &pkce_challenge(&verifier))
+            .map_err(bad_gateway)?;
+
+    // Signed, so the callback only trusts flows this server started
+    let flow = format!(
+        "{}.{}.{}.{}",
+        state,
+        nonce,
+        verifier,
+        invite.unwrap_or_default()
+    );
+    let mac = token_digest(&config.token_secret, flow.as_bytes());
+
+    let cookie = Cookie::build(FLOW_COOKIE, format!("{}.{}", flow, mac))
+        .path("/oidc")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::minutes(FLOW_MINUTES))
+        .finish();
+
+    Ok(HttpResponse::Found()
+        .insert_header((LOCATION, location))
+        .cookie(cookie)
+        .finish())
+}
+
+// Reads the state, nonce, PKCE verifier and invite code from the flow cookie
+fn read_flow(
+    req: &HttpRequest,
+    config: &Config,
+) -> Option<(String, String, String, Option<String>)> {
+    let cookie = req.cookie(FLOW_COOKIE)?;
+    let (flow, mac) = cookie.value().rsplit_once('.')?;
+
+    if token_digest(&config.token_secret, flow.as_bytes()) != mac {
+        return None;
+    }
+
+    let mut parts = flow.split('.').map(str::to_owned);
+    Some((
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        Some(parts.next()?).filter(|code| !code.is_empty()),
+    ))
+}
+
+// Sends the browser on to the frontend, dropping the flow cookie
+fn redirect(location: String, session: Option<Cookie<'static>>) -> HttpResponse {
+    let mut response = HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish();
+
+    let flow = Cookie::build(FLOW_COOKIE, "").path("/oidc").finish();
+    response.add_removal_cookie(&flow).unwrap();
+
+    if let Some(session) = session {
+        response.add_cookie(&session).unwrap();
+    }
+
+    response
+}
+
+// GET /oidc/callback
+// Takes in the provider's redirect and the flow cookie set by GET /oidc/login
+// On success, logs in the user linked to the provider account, creating one on first login,
+// and redirects to the frontend with the token cookie
+// Creating an account follows REGISTRATION_MODE, claiming the invite given to GET /oidc/login
+// With a session cookie, links the provider account to the logged in user instead
+// If the user has two-factor authentication, redirects to the frontend's two-factor page
+// with a login challenge
+// If the flow is invalid or expired, returns 400 Bad Request
+// If the account is pending verification, suspended or banned, returns 403 Forbidden
+// If registration is closed, or invite-only without a usable invite, and the provider
+// account has no user, returns 403 Forbidden
+// If the provider account is linked to another user, returns 409 Conflict
+// If the provider can't be reached or answers wrongly, returns 502 Bad Gateway
+pub async fn callback(
+    Query(params): Query<Callback>,
+    req: HttpRequest,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: Option<token::Model>,
+) -> Result<HttpResponse, InternalError<DbErr>> {
+    let oidc = sso(&config)?;
+
+    if let Some(error) = params.error {
+        return Err(fail(
+            format!(
+                "provider refused the login: {}",
+                params.error_description.unwrap_or(error)
+            ),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let (state, nonce, verifier, invite_code) =
+        read_flow(&req, &config).ok_or_else(|| bad_request("login expired, start again"))?;
+    if params.state.as_deref() != Some(state.as_str()) {
+        return Err(bad_request("login state does not match"));
+    }
+    let code = params
+        .code
+        .ok_or_else(|| bad_request("missing authorization code"))?;
+
+    let discovery = oidc::discover(oidc).await.map_err(bad_gateway)?;
+    let claims = oidc::exchange_code(&discovery, oidc, &code, &verifier, &nonce)
+        .await
+        .map_err(bad_gateway)?;
+
+    let linked = user::Entity::find()
+        .filter(user::Column::OidcSubject.eq(claims.sub.as_str()))
+        .one(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    // A logged in user is adding SSO to their account
+    if let Some(token) = token.filter(|t| !t.is_access_token()) {
+        let current = user::Entity::find_by_id(token.user_id)
+            .one(db.as_ref())
+            .await
+            .map_err(to_internal_error)?
+            .ok_or_else(|| bad_request("invalid token"))?;
+
+        let taken = linked.is_some_and(|u| u.id != current.id)
+            || current
+                .oidc_subject
+                .as_ref()
+                .is_some_and(|sub| *sub != claims.sub);
+        if taken {
+            return Err(fail(
+                String::from("provider account is linked to another user"),
+                StatusCode::CONFLICT,
+            ));
+        }
+
+        user::ActiveModel {
+            id: Set(current.id),
+            oidc_subject: Set(Some(claims.sub)),
+            ..Default::default()
+        }
+        .update(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+        return Ok(redirect(config.frontend_url.clone(), None));
+    }
+
+    let user = match linked {
+        Some(user) => user,
+        None => match link_by_email(db.as_ref(), oidc, &claims).await? {
+            Some(user) => user,
+            None => {
+                let invite = registration_invite(db.as_ref(), &config, invite_code).await?;
+                let user = create_user(db.as_ref(), &config, claims, invite.as_ref()).await?;
+                let detail = match &invite {
+                    Some(invite) => format!("sso, invite {}", invite.id),
+                    None => String::from("sso"),
+                };
+                audit::record(
+                    db.as_ref(),
+                    &req,
+                    audit_event::REGISTER,
+                    Some(user.id),
+                    Some(&user.username),
+                    Some(&detail),
+                )
+                .await;
+                user
+            }
+        },
+    };
+
+    if let Some(message) = user.status_error(Utc::now().naive_utc()) {
+        audit::record(
+            db.as_ref(),
+            &req,
+            audit_event::LOGIN_FAILURE,
+            Some(user.id),
+            Some(&user.username),
+            Some(message),
+        )
+        .await;
+        return Err(fail(message.to_owned(), StatusCode::FORBIDDEN));
+    }
+
+    if user.totp_enabled {
+        audit::record(
+            db.as_ref(),
+            &req,
+            audit_event::LOGIN_CHALLENGE,
+            Some(user.id),
+            Some(&user.username),
+            Some("sso"),
+        )
+        .await;
+
+        let challenge = create_challenge(db.as_ref(), &config, user.id)
+            .await
+            .map_err(to_internal_error)?;
+
+        return Ok(redirect(
+            format!("{}/login/2fa?challenge={}", config.frontend_url, challenge),
+            None,
+        ));
+    }
+
+    audit::record(
+        db.as_ref(),
+        &req,
+        audit_event::LOGIN_SUCCESS,
+        Some(user.id),
+        Some(&user.username),
+        Some("sso"),
+    )
+    .await;
+
+    let session = new_session(&req, db.as_ref(), &config, user.id).await?;
+
+    Ok(redirect(config.frontend_url.clone(), Some(session)))
+}
+
+// Links the provider account to the unlinked user with the same verified email, if allowed
+// A local account whose email was never verified is refused, its owner has to log in and link
+async fn link_by_email(
+    db: &DatabaseConnection,
+    oidc: &OidcConfig,
+    claims: &Claims,
+) -> Result<Option<user::Model>, InternalError<DbErr>> {
+    let email = match &claims.email {
+        Some(email) if oidc.link_by_email && claims.email_verified => email.trim().to_lowercase(),
+        _ => return Ok(None),
+    };
+
+    let user = match user::Entity::find()
+        .filter(user::Column::Email.eq(email))
+        .filter(user::Column::OidcSubject.is_null())
+        .one(db)
+        .await
+        .map_err(to_internal_error)?
+    {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    if user.email_verified_at.is_none() {
+        return Err(fail(
+            String::from(
+                "an account with this email exists, log in and sign in with SSO from there to link it",
+            ),
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    user::ActiveModel {
+        id: Set(user.id),
+        oidc_subject: Set(Some(claims.sub.clone())),
+        ..Default::default()
+    }
+    .update(db)
+    .await
+    .map(Some)
+    .map_err(to_internal_error)
+}
+
+// Checks a first SSO login may create an account, the same as POST /register would
+// Returns the invite to claim, any given invite is used like a registration's
+async fn registration_invite(
+    db: &DatabaseConnection,
+    config: &Config,
+    code: Option<String>,
+) -> Result<Option<invite_model::Model>, InternalError<DbErr>> {
+    check_registration_open(config)?;
+
+    let code = match code {
+        Some(code) => code,
+        None if config.registration_mode == RegistrationMode::InviteOnly => {
+            return Err(fail(
+                String::from("an invite is required, sign in with SSO from an invite link"),
+                StatusCode::FORBIDDEN,
+            ))
+        }
+        None => return Ok(None),
+    };
+
+    invite::find_usable(db, &code)
+        .await
+        .map_err(to_internal_error)?
+        .map(Some)
+        .ok_or_else(|| fail(invite::unusable().message, StatusCode::FORBIDDEN))
+}
+
+// Creates a user for the provider account, without a password
+// The username follows the provider's, with a number added when it is taken
+// Claiming the invite and creating the user succeed or fail together
+async fn create_user(
+    db: &DatabaseConnection,
+    config: &Config,
+    claims: Claims,
+    invite: Option<&invite_model::Model>,
+) -> Result<user::Model, InternalError<DbErr>> {
+    let allowed = |c: &char| c.is_ascii_alphanumeric() || config.username_extra_chars.contains(*c);
+    let base: String = claims
+        .preferred_username
+        .as_deref()
+        .or_else(|| claims.email.as_deref().and_then(|e| e.split('@').next()))
+        .unwrap_or("user")
+        .chars()
+        .filter(allowed)
+        .take(config.username_max_len.saturating_sub(5))
+        .collect();
+
+    let now = Utc::now().naive_utc();
+    let email = match claims.email.as_deref() {
+        Some(email) if claims.email_verified => {
+            let email = email.trim().to_lowercase();
+            let taken = user::Entity::find()
+                .filter(user::Column::Email.eq(email.as_str()))
+                .one(db)
+                .await
+                .map_err(to_internal_error)?
+                .is_some();
+            Some(email).filter(|_| !taken)
+        }
+        _ => None,
+    };
+
+    let txn = db.begin().await.map_err(to_internal_error)?;
+
+    if let Some(invite) = invite {
+        if !invite::claim(&txn, invite.id)
+            .await
+            .map_err(to_internal_error)?
+        {
+            return Err(fail(invite::unusable().message, StatusCode::FORBIDDEN));
+        }
+    }
+
+    for attempt in 0..5 {
+        let username = match attempt {
+            0 => base.clone(),
+            _ => format!("{}-{}", base, rand::thread_rng().gen_range(1000..10000)),
+        };
+
+        if !validation::username(config, &username).is_empty()
+            || username_taken(db, &username)
+                .await
+                .map_err(to_internal_error)?
+        {
+            continue;
+        }
+
+        let user = user::ActiveModel {
+            username: Set(username),
+            password: Set(NO_PASSWORD.to_owned()),
+            email_verified_at: Set(email.as_ref().map(|_| now)),
+            email: Set(email),
+            role: Set(Role::User),
+            totp_enabled: Set(false),
+            status: Set(Status::Active),
+            oidc_subject: Set(Some(claims.sub)),
+            invited_by: Set(invite.map(|invite| invite.created_by)),
+            invite_id: Set(invite.map(|invite| invite.id)),
+            created_at: Set(now),
+            ..Default::default()
+        }
+        .insert(&txn)
+        .await
+        .map_err(to_internal_error)?;
+
+        txn.commit().await.map_err(to_internal_error)?;
+        return Ok(user);
+    }
+
+    Err(fail(
+        String::from("no free username for the provider account"),
+        StatusCode::CONFLICT,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::ResponseError;
+
+    use super::*;
+
+    fn config(mode: RegistrationMode) -> Config {
+        let mut config = Config::from_env();
+        config.registration_mode = mode;
+        config
+    }
+
+    // Refusals are decided before the database is asked for the invite
+    async fn status(mode: RegistrationMode, code: Option<&str>) -> Option<StatusCode> {
+        let db = DatabaseConnection::Disconnected;
+        registration_invite(&db, &config(mode), code.map(str::to_owned))
+            .await
+            .err()
+            .map(|e| e.status_code())
+    }
+
+    #[actix_web::test]
+    async fn first_login_creates_accounts_while_registration_is_open() {
+        assert_eq!(status(RegistrationMode::Open, None).await, None);
+    }
+
+    #[actix_web::test]
+    async fn first_login_is_refused_while_registration_is_closed() {
+        assert_eq!(
+            status(RegistrationMode::Closed, None).await,
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            status(RegistrationMode::Closed, Some("afi_0")).await,
+            Some(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[actix_web::test]
+    async fn first_login_needs_an_invite_while_registration_is_invite_only() {
+        assert_eq!(
+            status(RegistrationMode::InviteOnly, None).await,
+            Some(StatusCode::FORBIDDEN)
+        );
+    }
+}
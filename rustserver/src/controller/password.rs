@@ -0,0 +1,281 @@
+use actix_web::{
+    error::InternalError,
+    http::{header::RETRY_AFTER, StatusCode},
+    web::{Data, Json},
+    HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use sea_orm::{
+    prelude::Uuid, ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait,
+    QueryFilter, Set,
+};
+
+use crate::{
+    config::Config,
+    crypto::token_digest,
+    mail::{Mailer, Message},
+    model::{
+        action_token, token,
+        user::{self, LoginResponse},
+    },
+};
+
+use super::{
+    auth::check_password_login, client_ip, hash_blocking, lockout, permission::check_session,
+    to_bad_request, to_internal_error, to_not_found, validation, verify_blocking,
+};
+
+// POST /me/password
+// Takes in JSON encoded user PasswordChange and user auth
+// On success, changes the password, revokes the user's other tokens and returns 200 OK
+// with JSON encoded LoginResponse
+// A wrong old password counts as a failed login
+// If the new password is too weak, returns 400 Bad Request
+// While the username or client IP is locked out, returns 429 Too Many Requests
+// On error, returns 500 Internal Server Error
+pub async fn change(
+    Json(change): Json<user::PasswordChange>,
+    req: HttpRequest,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<Json<LoginResponse>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let user = user::Entity::find_by_id(token.user_id)
+        .one(db.as_ref())
+        .await
+        .transpose()
+        .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+        .and_then(std::convert::identity)
+        .map_err(to_not_found)?;
+
+    let ip = client_ip(&req);
+    let keys = lockout::keys(&config, &user.username, ip.as_deref());
+
+    if let Some(until) = lockout::locked_until(db.as_ref(), &keys)
+        .await
+        .map_err(to_internal_error)?
+    {
+        let response = HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, lockout::retry_after(until).to_string()))
+            .json(LoginResponse {
+                status: false,
+                message: "too many failed logins, try again later",
+            });
+        return Err(InternalError::from_response(
+            DbErr::Custom("locked out".to_string()),
+            response,
+        ));
+    }
+
+    if !verify_blocking(&change.old_password, &user.password).await? {
+        lockout::record_failure(db.as_ref(), &config, &keys)
+            .await
+            .map_err(to_internal_error)?;
+        return Ok(Json(LoginResponse {
+            status: false,
+            message: "invalid password",
+        }));
+    }
+
+    check_password(&config, &change.new_password, &user.username)?;
+
+    set_password(db.as_ref(), &config, user.id, &change.new_password).await?;
+
+    token::Entity::delete_many()
+        .filter(token::Column::UserId.eq(user.id))
+        .filter(token::Column::Id.ne(token.id))
+        .exec(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    Ok(Json(LoginResponse {
+        status: true,
+        message: "password changed",
+    }))
+}
+
+// POST /password-reset/request
+// Takes in JSON encoded user ResetRequest
+// If the user has an email address, mails them a single-use reset link
+// Always returns 200 OK with the same JSON encoded LoginResponse, so it can't reveal users
+// If password login is disabled, returns 403 Forbidden
+pub async fn request_reset(
+    Json(request): Json<user::ResetRequest>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    mailer: Data<dyn Mailer>,
+) -> Result<Json<LoginResponse>, InternalError<DbErr>> {
+    check_password_login(&config)?;
+
+    let response = Json(LoginResponse {
+        status: true,
+        message: "if the account has an email address, a reset link was sent",
+    });
+
+    let user = match user::Entity::find()
+        .filter(user::Column::Username.eq(request.username))
+        .one(db.as_ref())
+        .await
+        .map_err(to_internal_error)?
+    {
+        Some(user) => user,
+        None => return Ok(response),
+    };
+
+    let email = match &user.email {
+        Some(email) => email.clone(),
+        None => return Ok(response),
+    };
+
+    // Only the latest link works
+    action_token::Entity::delete_many()
+        .filter(action_token::Column::UserId.eq(user.id))
+        .filter(action_token::Column::Purpose.eq(action_token::PASSWORD_RESET))
+        .exec(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    let uuid = Uuid::new_v4();
+    let now = Utc::now();
+
+    action_token::ActiveModel {
+        hash: Set(token_digest(&config.token_secret, uuid.as_bytes())),
+        user_id: Set(user.id),
+        purpose: Set(action_token::PASSWORD_RESET.to_owned()),
+        expires_at: Set((now + config.reset_token_lifetime).naive_utc()),
+        attempts: Set(0),
+        created_at: Set(now.naive_utc()),
+        ..Default::default()
+    }
+    .insert(db.as_ref())
+    .await
+    .map_err(to_internal_error)?;
+
+    let message = Message {
+        to: email,
+        subject: String::from("Reset your aeroFans password"),
+        body: format!(
+            "Hi {},\r\n\r\nUse this link to choose a new password, it expires in {} minutes:\r\n{}/reset?token={}\r\n\r\nIf you didn't ask for this, ignore this message.",
+            user.username,
+            config.reset_token_lifetime.num_minutes(),
+            config.frontend_url,
+            uuid
+        ),
+    };
+
+    // Answered the same as an unknown user, so a failure can't reveal the account
+    if let Err(e) = mailer.send(&message) {
+        eprintln!("failed to mail a reset link: {}", e);
+    }
+
+    Ok(response)
+}
+
+// POST /password-reset/confirm
+// Takes in JSON encoded user ResetConfirm
+// On success, consumes the reset token, sets the password, revokes every token of the user
+// and returns 200 OK with JSON encoded LoginResponse
+// If the reset token is invalid or expired, returns 400 Bad Request
+// If the new password is too weak, returns 400 Bad Request
+// If password login is disabled, returns 403 Forbidden
+pub async fn confirm_reset(
+    Json(confirm): Json<user::ResetConfirm>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+) -> Result<Json<LoginResponse>, InternalError<DbErr>> {
+    check_password_login(&config)?;
+
+    let invalid = || {
+        InternalError::new(
+            DbErr::Custom("invalid reset token".to_string()),
+            StatusCode::BAD_REQUEST,
+        )
+    };
+
+    let uuid = Uuid::parse_str(&confirm.token).map_err(|_| invalid())?;
+
+    let reset = action_token::Entity::find()
+        .filter(action_token::Column::Hash.eq(token_digest(&config.token_secret, uuid.as_bytes())))
+        .filter(action_token::Column::Purpose.eq(action_token::PASSWORD_RESET))
+        .filter(action_token::Column::ExpiresAt.gt(Utc::now().naive_utc()))
+        .one(db.as_ref())
+        .await
+        .map_err(to_internal_error)?
+        .ok_or_else(invalid)?;
+
+    let user = user::Entity::find_by_id(reset.user_id)
+        .one(db.as_ref())
+        .await
+        .transpose()
+        .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+        .and_then(std::convert::identity)
+        .map_err(to_not_found)?;
+
+    // A weak password leaves the token for another try
+    check_password(&config, &confirm.password, &user.username)?;
+
+    // Consume first, so a token can't be redeemed twice
+    let consumed = action_token::Entity::delete_many()
+        .filter(action_token::Column::Id.eq(reset.id))
+        .exec(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+    if consumed.rows_affected == 0 {
+        return Err(invalid());
+    }
+
+    set_password(db.as_ref(), &config, reset.user_id, &confirm.password).await?;
+
+    token::Entity::delete_many()
+        .filter(token::Column::UserId.eq(reset.user_id))
+        .exec(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    Ok(Json(LoginResponse {
+        status: true,
+        message: "password reset",
+    }))
+}
+
+// Checks a new password against the strength rules
+// If it is too weak, fails with 400 Bad Request naming the broken rules
+fn check_password(
+    config: &Config,
+    password: &str,
+    username: &str,
+) -> Result<(), InternalError<DbErr>> {
+    let errors = validation::password(config, password, username);
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    let message = errors
+        .into_iter()
+        .map(|e| e.message)
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(to_bad_request(DbErr::Custom(message)))
+}
+
+// Hashes and stores a new password for the user
+async fn set_password(
+    db: &DatabaseConnection,
+    config: &Config,
+    user_id: i64,
+    password: &str,
+) -> Result<(), InternalError<DbErr>> {
+    let password = hash_blocking(password, &config.password_params).await?;
+
+    user::ActiveModel {
+        id: Set(user_id),
+        password: Set(password),
+        ..Default::default()
+    }
+    .update(db)
+    .await
+    .map(|_| ())
+    .map_err(to_internal_error)
+}
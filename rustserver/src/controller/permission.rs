@@ -0,0 +1,132 @@
+use actix_web::{error::InternalError, http::StatusCode};
+use chrono::Utc;
+use sea_orm::{ActiveModelTrait, ConnectionTrait, DatabaseConnection, DbErr, EntityTrait, Set};
+
+use crate::{
+    config::Config,
+    model::{
+        moderation,
+        token::{self, Scope},
+        user::{self, Role},
+    },
+};
+
+use super::{to_internal_error, to_not_found};
+
+// What the actor may do to a piece of content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    // The actor wrote it
+    Owner,
+    // The actor is moderating someone else's content
+    Moderator,
+}
+
+// Loads the user behind a token
+pub async fn actor(
+    db: &DatabaseConnection,
+    token: &token::Model,
+) -> Result<user::Model, InternalError<DbErr>> {
+    user::Entity::find_by_id(token.user_id)
+        .one(db)
+        .await
+        .transpose()
+        .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+        .and_then(std::convert::identity)
+        .map_err(to_not_found)
+}
+
+// Checks the token may be used for scope
+pub fn check_scope(token: &token::Model, scope: Scope) -> Result<(), InternalError<DbErr>> {
+    if token.allows(scope) {
+        Ok(())
+    } else {
+        Err(InternalError::new(
+            DbErr::Custom(format!("token lacks scope {}", scope.as_str())),
+            StatusCode::FORBIDDEN,
+        ))
+    }
+}
+
+// Checks the token is a login session, personal access tokens can't manage the account
+pub fn check_session(token: &token::Model) -> Result<(), InternalError<DbErr>> {
+    if token.is_access_token() {
+        Err(InternalError::new(
+            DbErr::Custom("requires a login session".to_string()),
+            StatusCode::FORBIDDEN,
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// Checks the actor may edit or delete content written by owner_id
+pub fn check_content(actor: &user::Model, owner_id: i64) -> Result<Access, InternalError<DbErr>> {
+    if actor.id == owner_id {
+        Ok(Access::Owner)
+    } else if actor.role >= Role::Moderator {
+        Ok(Access::Moderator)
+    } else {
+        Err(InternalError::new(
+            DbErr::Custom("not real author".to_string()),
+            StatusCode::UNAUTHORIZED,
+        ))
+    }
+}
+
+// Checks the actor may manage other users' roles
+// Admins may be required to have two-factor authentication before acting as admins
+pub fn check_admin(actor: &user::Model, config: &Config) -> Result<(), InternalError<DbErr>> {
+    if actor.role == Role::Admin && config.require_admin_2fa && !actor.totp_enabled {
+        Err(InternalError::new(
+            DbErr::Custom("admins must enable two-factor authentication".to_string()),
+            StatusCode::FORBIDDEN,
+        ))
+    } else if actor.role == Role::Admin {
+        Ok(())
+    } else {
+        Err(InternalError::new(
+            DbErr::Custom("not an admin".to_string()),
+            StatusCode::FORBIDDEN,
+        ))
+    }
+}
+
+// Checks the actor may view moderation records
+pub fn check_moderator(actor: &user::Model) -> Result<(), InternalError<DbErr>> {
+    if actor.role >= Role::Moderator {
+        Ok(())
+    } else {
+        Err(InternalError::new(
+            DbErr::Custom("not a moderator".to_string()),
+            StatusCode::FORBIDDEN,
+        ))
+    }
+}
+
+// Records a moderator action in the moderation log
+// Pass the transaction making the change, so the action and its entry commit together
+pub async fn log<'a, C: ConnectionTrait<'a>>(
+    db: &'a C,
+    moderator: &user::Model,
+    action: &str,
+    target_user_id: i64,
+    post_id: Option<i64>,
+    reply_id: Option<i64>,
+    detail: Option<String>,
+) -> Result<(), InternalError<DbErr>> {
+    moderation::ActiveModel {
+        moderator_id: Set(moderator.id),
+        action: Set(action.to_owned()),
+        target_user_id: Set(target_user_id),
+        post_id: Set(post_id),
+        reply_id: Set(reply_id),
+        detail: Set(detail),
+        created_at: Set(Utc::now().naive_utc()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await
+    .map(|_| ())
+    .map_err(to_internal_error)
+}
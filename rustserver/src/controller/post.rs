@@ -1,43 +1,153 @@
+use std::collections::HashSet;
+
 use actix_web::{
     error::InternalError,
-    http::StatusCode,
-    web::{Data, Json, Path},
+    web::{Data, Json, Path, Query},
     HttpResponse,
 };
 use chrono::Utc;
 use sea_orm::{
-    ActiveModelTrait, DatabaseConnection, DbErr, EntityTrait, IntoActiveModel, QuerySelect,
-    RelationTrait, Set, Value,
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection, DbErr,
+    EntityTrait, IntoActiveModel, NotSet, Order, QueryFilter, QueryOrder, QuerySelect,
+    RelationTrait, Select, Set, Value,
+};
+
+use crate::{
+    config::Config,
+    model::{
+        board, moderation,
+        page::{Cursor, CursorKey, Page, PageQuery},
+        post,
+        token::{self, Scope},
+        user,
+    },
 };
 
-use crate::model::{post, token, user};
+use super::{
+    invalid_cursor, page_cursor,
+    permission::{actor, check_content, check_scope, log, Access},
+    tag::{normalise_all, normalise_filter, set_tags, tags_of},
+    to_bad_request, to_internal_error, to_not_found, to_ok,
+};
+
+const MAX_TITLE_LEN: usize = 200;
+const MAX_SUMMARY_LEN: usize = 500;
+const MAX_TEXT_LEN: usize = 20000;
+
+const REPLY_COUNT: &str = "(SELECT COUNT(*) FROM replies WHERE replies.post_id = posts.id \
+    AND replies.deleted_at IS NULL)";
+const LAST_ACTIVITY: &str = "COALESCE((SELECT MAX(replies.created_at) FROM replies \
+    WHERE replies.post_id = posts.id AND replies.deleted_at IS NULL), posts.created_at)";
+const TAGS: &str = "COALESCE((SELECT JSON_AGG(tags.name ORDER BY tags.name) FROM post_tags \
+    INNER JOIN tags ON tags.id = post_tags.tag_id WHERE post_tags.post_id = posts.id), '[]')";
+
+// Selects post Outputs, with the author's username, board, tags, reply count, last
+// activity and the vote of the user with voter_id
+fn outputs(query: Select<post::Entity>, voter_id: Option<i64>) -> Select<post::Entity> {
+    query
+        .join(sea_orm::JoinType::InnerJoin, post::Relation::User.def())
+        .join(sea_orm::JoinType::InnerJoin, post::Relation::Board.def())
+        .column(user::Column::Username)
+        .column_as(board::Column::Slug, "board")
+        .column_as(Expr::cust(TAGS), "tags")
+        .column_as(Expr::cust(REPLY_COUNT), "reply_count")
+        .column_as(Expr::cust(LAST_ACTIVITY), "last_activity_at")
+        .column_as(
+            Expr::cust_with_values(
+                "(SELECT votes.value FROM votes WHERE votes.post_id = posts.id \
+                AND votes.reply_id IS NULL AND votes.user_id = ?)",
+                vec![voter_id],
+            ),
+            "my_vote",
+        )
+}
+
+// Trims a required field, failing with 400 Bad Request when empty or too long
+fn required(value: String, max_len: usize, name: &str) -> Result<String, InternalError<DbErr>> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(to_bad_request(DbErr::Custom(format!(
+            "{} is required",
+            name
+        ))));
+    }
+    if value.chars().count() > max_len {
+        return Err(to_bad_request(DbErr::Custom(format!(
+            "{} is longer than {} characters",
+            name, max_len
+        ))));
+    }
+    Ok(value.to_owned())
+}
 
-use super::{to_internal_error, to_not_found, to_ok};
+// Trims the summary, empty ones become None
+fn summary(value: String) -> Result<Option<String>, InternalError<DbErr>> {
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    required(value, MAX_SUMMARY_LEN, "summary").map(Some)
+}
+
+// Finds the board with the slug
+// If there is none, fails with 400 Bad Request
+async fn find_board(
+    db: &DatabaseConnection,
+    slug: &str,
+) -> Result<board::Model, InternalError<DbErr>> {
+    board::Entity::find()
+        .filter(board::Column::Slug.eq(slug))
+        .one(db)
+        .await
+        .map_err(to_internal_error)?
+        .ok_or_else(|| to_bad_request(DbErr::Custom(format!("no board {}", slug))))
+}
 
 // POST /post
 // Takes in JSON encoded post Input and user auth
+// The title and text are required, the summary and tags are optional
+// Without a board, the post goes to the default board
 // On success, returns 200 OK with JSON encoded post Output
+// If a field is missing or too long, a tag is invalid or there are too many, or the board
+// does not exist, returns 400 Bad Request
 // On error, returns 500 Internal Server Error
 pub async fn create(
-    Json(input_post): Json<post::Input>,
+    Json(input): Json<post::Input>,
     db: Data<DatabaseConnection>,
+    config: Data<Config>,
     token: token::Model,
 ) -> Result<Json<post::Output>, InternalError<DbErr>> {
-    let input_post = input_post.into_active_model();
+    check_scope(&token, Scope::PostsWrite)?;
+
+    let board = find_board(
+        db.as_ref(),
+        input.board.as_deref().unwrap_or(&config.default_board),
+    )
+    .await?;
+    let tags = normalise_all(&input.tags)?;
+
     let input_post = post::ActiveModel {
         user_id: Set(token.user_id),
+        board_id: Set(board.id),
+        title: Set(required(input.title, MAX_TITLE_LEN, "title")?),
+        summary: Set(input.summary.map(summary).transpose()?.flatten()),
+        text: Set(required(input.text, MAX_TEXT_LEN, "text")?),
         created_at: Set(Utc::now().naive_utc()),
-        ..input_post
+        updated_at: Set(None),
+        score: Set(0),
+        ..Default::default()
     };
 
-    let post = input_post
-        .insert(db.as_ref())
+    // The post and its tags are saved together
+    let txn = db.begin().await.map_err(to_internal_error)?;
+
+    let post = input_post.insert(&txn).await.map_err(to_internal_error)?;
+    set_tags(&txn, post.id, &tags)
         .await
         .map_err(to_internal_error)?;
 
-    post::Entity::find_by_id(post.id)
-        .join(sea_orm::JoinType::InnerJoin, post::Relation::User.def())
-        .column(user::Column::Username)
+    txn.commit().await.map_err(to_internal_error)?;
+
+    outputs(post::Entity::find_by_id(post.id), Some(token.user_id))
         .into_model::<post::Output>()
         .one(db.as_ref())
         .await
@@ -49,53 +159,172 @@ pub async fn create(
 }
 
 // GET /post/all
-// On success, returns 200 OK with JSON encoded post Outputs
+// Takes in query encoded post ListQuery and page PageQuery
+// Optionally takes in user auth, access tokens need the posts:read scope
+// On success, returns 200 OK with a JSON encoded Page of post Outputs, newest first unless
+// sorted otherwise
+// If a parameter or the cursor is invalid, returns 400 Bad Request
 // On error, returns 500 Internal Server Error
 pub async fn read_all(
+    Query(list): Query<post::ListQuery>,
+    Query(page): Query<PageQuery>,
     db: Data<DatabaseConnection>,
-) -> Result<Json<Vec<post::Output>>, InternalError<DbErr>> {
-    post::Entity::find()
-        .join(sea_orm::JoinType::InnerJoin, post::Relation::User.def())
-        .column(user::Column::Username)
+    token: Option<token::Model>,
+) -> Result<Json<Page<post::Output>>, InternalError<DbErr>> {
+    if let Some(token) = &token {
+        check_scope(token, Scope::PostsRead)?;
+    }
+
+    list_posts(
+        db.as_ref(),
+        post::Entity::find(),
+        list,
+        page,
+        token.map(|token| token.user_id),
+    )
+    .await
+    .map(Json)
+}
+
+// Reads a page of the posts query selects, sorted and filtered as asked, with the votes of
+// the user with voter_id
+pub async fn list_posts(
+    db: &DatabaseConnection,
+    query: Select<post::Entity>,
+    list: post::ListQuery,
+    page: PageQuery,
+    voter_id: Option<i64>,
+) -> Result<Page<post::Output>, InternalError<DbErr>> {
+    let mut query = outputs(query, voter_id);
+
+    if let Some(author) = &list.author {
+        query = query.filter(Expr::cust_with_values(
+            "LOWER(users.username) = ?",
+            vec![author.to_lowercase()],
+        ));
+    }
+    if let (Some(after), Some(before)) = (list.created_after, list.created_before) {
+        if after >= before {
+            return Err(to_bad_request(DbErr::Custom(
+                "created_after must be before created_before".to_string(),
+            )));
+        }
+    }
+    if let Some(after) = list.created_after {
+        query = query.filter(post::Column::CreatedAt.gt(after));
+    }
+    if let Some(before) = list.created_before {
+        query = query.filter(post::Column::CreatedAt.lt(before));
+    }
+    match list.has_replies {
+        Some(true) => query = query.filter(Expr::cust(&format!("{} > 0", REPLY_COUNT))),
+        Some(false) => query = query.filter(Expr::cust(&format!("{} = 0", REPLY_COUNT))),
+        None => {}
+    }
+    if let Some(tag) = &list.tag {
+        let tags = normalise_filter(tag)?;
+        query = query.filter(Expr::cust_with_values(
+            &format!(
+                "posts.id IN (SELECT post_tags.post_id FROM post_tags INNER JOIN tags \
+                ON tags.id = post_tags.tag_id WHERE tags.name IN ({}))",
+                vec!["?"; tags.len()].join(", ")
+            ),
+            tags,
+        ));
+    }
+
+    // Every order is on a key then id, so cursors can resume after the last item
+    let (key, descending) = match list.sort {
+        post::Sort::Newest => ("posts.created_at", true),
+        post::Sort::Oldest => ("posts.created_at", false),
+        post::Sort::MostReplies => (REPLY_COUNT, true),
+        post::Sort::RecentActivity => (LAST_ACTIVITY, true),
+    };
+    let order = || if descending { Order::Desc } else { Order::Asc };
+
+    if let Some(cursor) = page_cursor(&page)? {
+        let value = match (cursor.key, list.sort) {
+            (CursorKey::Count(count), post::Sort::MostReplies) => Value::from(count),
+            (CursorKey::Time(time), sort) if sort != post::Sort::MostReplies => Value::from(time),
+            _ => return Err(invalid_cursor()),
+        };
+        query = query.filter(Expr::cust_with_values(
+            &format!(
+                "({}, posts.id) {} (?, ?)",
+                key,
+                if descending { "<" } else { ">" }
+            ),
+            vec![value, Value::from(cursor.id)],
+        ));
+    }
+
+    let limit = page.limit();
+
+    query
+        .order_by(Expr::cust(key), order())
+        .order_by(post::Column::Id, order())
+        .limit(limit + 1)
         .into_model::<post::Output>()
-        .all(db.as_ref())
+        .all(db)
         .await
-        .map(Json)
+        .map(|posts| {
+            Page::new(posts, limit, |post| Cursor {
+                key: match list.sort {
+                    post::Sort::Newest | post::Sort::Oldest => CursorKey::Time(post.created_at),
+                    post::Sort::MostReplies => CursorKey::Count(post.reply_count),
+                    post::Sort::RecentActivity => CursorKey::Time(post.last_activity_at),
+                },
+                id: post.id,
+            })
+        })
         .map_err(to_internal_error)
 }
 
 // GET /post/{post_id}
+// Optionally takes in user auth, access tokens need the posts:read scope
 // On success, returns 200 OK with JSON encoded post Output
 // If post_id does not exist, returns 404 Not Found
 pub async fn read(
     param: Path<i64>,
     db: Data<DatabaseConnection>,
+    token: Option<token::Model>,
 ) -> Result<Json<post::Output>, InternalError<DbErr>> {
+    if let Some(token) = &token {
+        check_scope(token, Scope::PostsRead)?;
+    }
+
     let post_id = param.into_inner();
 
-    post::Entity::find_by_id(post_id)
-        .join(sea_orm::JoinType::InnerJoin, post::Relation::User.def())
-        .column(user::Column::Username)
-        .into_model::<post::Output>()
-        .one(db.as_ref())
-        .await
-        .transpose()
-        .ok_or_else(|| DbErr::RecordNotFound(String::new()))
-        .and_then(std::convert::identity)
-        .map(Json)
-        .map_err(to_not_found)
+    outputs(
+        post::Entity::find_by_id(post_id),
+        token.map(|token| token.user_id),
+    )
+    .into_model::<post::Output>()
+    .one(db.as_ref())
+    .await
+    .transpose()
+    .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+    .and_then(std::convert::identity)
+    .map(Json)
+    .map_err(to_not_found)
 }
 
 // PATCH /post/{post_id}
-// Takes in JSON encoded post Input and token
+// Takes in JSON encoded post Update and token
+// Omitted fields are left alone, an empty summary clears it, a board moves the post and
+// tags replace the post's tags
 // On success, updates and returns 200 OK with JSON encoded post Output
+// If a field is empty or too long, a tag is invalid or there are too many, or the board
+// does not exist, returns 400 Bad Request
 // If post_id does not exist, returns 404 Not Found
 pub async fn update(
-    Json(input_post): Json<post::Input>,
+    Json(input): Json<post::Update>,
     param: Path<i64>,
     db: Data<DatabaseConnection>,
     token: token::Model,
 ) -> Result<Json<post::Output>, InternalError<DbErr>> {
+    check_scope(&token, Scope::PostsWrite)?;
+
     let post_id = param.into_inner();
 
     let post = post::Entity::find_by_id(post_id)
@@ -106,26 +335,93 @@ pub async fn update(
         .and_then(std::convert::identity)
         .map_err(to_not_found)?;
 
-    if post.user_id != token.user_id {
-        return Err(InternalError::new(
-            DbErr::Custom("not real author".to_string()),
-            StatusCode::UNAUTHORIZED,
-        ));
+    let actor = actor(db.as_ref(), &token).await?;
+    let access = check_content(&actor, post.user_id)?;
+
+    let board = match &input.board {
+        Some(slug) => Some(find_board(db.as_ref(), slug).await?),
+        None => None,
+    };
+    let tags = input.tags.as_deref().map(normalise_all).transpose()?;
+    let old_tags = tags_of(db.as_ref(), post.id)
+        .await
+        .map_err(to_internal_error)?;
+
+    let input_post = post::ActiveModel {
+        id: Set(post.id),
+        board_id: match &board {
+            Some(board) => Set(board.id),
+            None => NotSet,
+        },
+        title: match input.title {
+            Some(title) => Set(required(title, MAX_TITLE_LEN, "title")?),
+            None => NotSet,
+        },
+        summary: match input.summary {
+            Some(value) => Set(summary(value)?),
+            None => NotSet,
+        },
+        text: match input.text {
+            Some(text) => Set(required(text, MAX_TEXT_LEN, "text")?),
+            None => NotSet,
+        },
+        updated_at: Set(Some(Utc::now().naive_utc())),
+        ..Default::default()
+    };
+
+    let txn = db.begin().await.map_err(to_internal_error)?;
+
+    let updated = input_post.update(&txn).await.map_err(to_internal_error)?;
+    if let Some(tags) = &tags {
+        set_tags(&txn, post.id, tags)
+            .await
+            .map_err(to_internal_error)?;
     }
 
-    let mut input_post = input_post.into_active_model();
+    if access == Access::Moderator {
+        if let Some(board) = board.filter(|board| board.id != post.board_id) {
+            let old_board = board::Entity::find_by_id(post.board_id)
+                .one(&txn)
+                .await
+                .map_err(to_internal_error)?
+                .map_or_else(|| post.board_id.to_string(), |old_board| old_board.slug);
 
-    input_post.set(post::Column::Id, Value::BigInt(Some(post_id)));
-    input_post.set(post::Column::UserId, Value::BigInt(Some(token.user_id)));
+            log(
+                &txn,
+                &actor,
+                moderation::MOVE_POST,
+                post.user_id,
+                Some(post.id),
+                None,
+                Some(format!("board {} -> {}", old_board, board.slug)),
+            )
+            .await?;
+        }
+        if updated.title != post.title
+            || updated.summary != post.summary
+            || updated.text != post.text
+            || tags.as_ref().is_some_and(|tags| {
+                tags.iter().collect::<HashSet<_>>() != old_tags.iter().collect::<HashSet<_>>()
+            })
+        {
+            log(
+                &txn,
+                &actor,
+                moderation::EDIT_POST,
+                post.user_id,
+                Some(post.id),
+                None,
+                Some(post.text),
+            )
+            .await?;
+        }
+    }
 
-    let post = input_post
-        .update(db.as_ref())
-        .await
-        .map_err(to_internal_error)?;
+    txn.commit().await.map_err(to_internal_error)?;
 
-    post::Entity::find_by_id(post.id)
-        .join(sea_orm::JoinType::InnerJoin, post::Relation::User.def())
-        .column(user::Column::Username)
+    let post = updated;
+
+    outputs(post::Entity::find_by_id(post.id), Some(token.user_id))
         .into_model::<post::Output>()
         .one(db.as_ref())
         .await
@@ -145,6 +441,8 @@ pub async fn delete(
     db: Data<DatabaseConnection>,
     token: token::Model,
 ) -> Result<HttpResponse, InternalError<DbErr>> {
+    check_scope(&token, Scope::PostsWrite)?;
+
     let post_id = param.into_inner();
 
     let post = post::Entity::find_by_id(post_id)
@@ -155,16 +453,32 @@ pub async fn delete(
         .and_then(std::convert::identity)
         .map_err(to_not_found)?;
 
-    if post.user_id != token.user_id {
-        return Err(InternalError::new(
-            DbErr::Custom("not real author".to_string()),
-            StatusCode::UNAUTHORIZED,
-        ));
-    }
+    let actor = actor(db.as_ref(), &token).await?;
+    let access = check_content(&actor, post.user_id)?;
 
-    post.into_active_model()
-        .delete(db.as_ref())
+    let txn = db.begin().await.map_err(to_internal_error)?;
+
+    let res = post
+        .clone()
+        .into_active_model()
+        .delete(&txn)
         .await
-        .map(to_ok)
-        .map_err(to_internal_error)
+        .map_err(to_internal_error)?;
+
+    if access == Access::Moderator {
+        log(
+            &txn,
+            &actor,
+            moderation::DELETE_POST,
+            post.user_id,
+            Some(post.id),
+            None,
+            Some(post.text),
+        )
+        .await?;
+    }
+
+    txn.commit().await.map_err(to_internal_error)?;
+
+    Ok(to_ok(res))
 }
@@ -1,22 +1,153 @@
+use std::collections::HashMap;
+
 use actix_web::{
     error::InternalError,
     http::StatusCode,
-    web::{Data, Json, Path},
+    web::{Data, Json, Path, Query},
     HttpResponse,
 };
 use chrono::Utc;
 use sea_orm::{
-    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, IntoActiveModel,
-    QueryFilter, QuerySelect, RelationTrait, Set,
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection, DbErr,
+    EntityTrait, IntoActiveModel, Iterable, QueryFilter, QueryOrder, QuerySelect, RelationTrait,
+    Select, Set, Value,
+};
+
+use crate::model::{
+    moderation,
+    page::{Cursor, CursorKey, Page, PageQuery},
+    reply,
+    token::{self, Scope},
 };
 
-use crate::model::{reply, token, user};
+use super::{
+    invalid_cursor, page_cursor,
+    permission::{actor, check_content, check_scope, log, Access},
+    to_bad_request, to_internal_error, to_not_found, to_ok,
+};
+
+// Deepest a reply may be nested, top-level replies are at 0
+const MAX_DEPTH: i32 = 32;
+
+// Selects reply Outputs, with the author's username and the vote of the user with voter_id
+// Placeholders of deleted replies don't say who wrote them
+fn outputs(query: Select<reply::Entity>, voter_id: Option<i64>) -> Select<reply::Entity> {
+    reply::Column::iter()
+        .filter(|column| !matches!(column, reply::Column::UserId))
+        .fold(query.select_only(), |query, column| query.column(column))
+        .join(sea_orm::JoinType::InnerJoin, reply::Relation::User.def())
+        .column_as(
+            Expr::cust("CASE WHEN replies.deleted_at IS NULL THEN replies.user_id END"),
+            "user_id",
+        )
+        .column_as(
+            Expr::cust("CASE WHEN replies.deleted_at IS NULL THEN users.username END"),
+            "username",
+        )
+        .column_as(
+            Expr::cust_with_values(
+                "(SELECT votes.value FROM votes WHERE votes.reply_id = replies.id \
+                AND votes.user_id = ?)",
+                vec![voter_id],
+            ),
+            "my_vote",
+        )
+}
+
+// Nests the replies below each top-level reply under it, oldest first
+async fn tree(
+    db: &DatabaseConnection,
+    post_id: i64,
+    roots: Vec<reply::Output>,
+    voter_id: Option<i64>,
+) -> Result<Vec<reply::Node>, InternalError<DbErr>> {
+    if roots.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // A path starts with the id of the top-level reply it is below
+    let descendants = outputs(
+        reply::Entity::find()
+            .filter(reply::Column::PostId.eq(post_id))
+            .filter(reply::Column::ParentReplyId.is_not_null())
+            .filter(Expr::cust_with_values(
+                &format!(
+                    "SPLIT_PART(replies.path, '/', 1) IN ({})",
+                    vec!["?"; roots.len()].join(", ")
+                ),
+                roots.iter().map(|root| root.id.to_string()),
+            )),
+        voter_id,
+    )
+    .order_by_asc(reply::Column::CreatedAt)
+    .order_by_asc(reply::Column::Id)
+    .into_model::<reply::Output>()
+    .all(db)
+    .await
+    .map_err(to_internal_error)?;
+
+    let mut children: HashMap<i64, Vec<reply::Output>> = HashMap::new();
+    for reply in descendants {
+        if let Some(parent_reply_id) = reply.parent_reply_id {
+            children.entry(parent_reply_id).or_default().push(reply);
+        }
+    }
+
+    Ok(roots
+        .into_iter()
+        .map(|root| node(root, &mut children))
+        .collect())
+}
 
-use super::{to_internal_error, to_not_found, to_ok};
+fn node(reply: reply::Output, children: &mut HashMap<i64, Vec<reply::Output>>) -> reply::Node {
+    let below = children.remove(&reply.id).unwrap_or_default();
+    reply::Node {
+        children: below
+            .into_iter()
+            .map(|child| node(child, children))
+            .collect(),
+        reply,
+    }
+}
+
+// Deletes placeholders left with no replies below them, from parent_reply_id upwards
+// Each placeholder is locked before it is checked, so a reply to it can't slip in meanwhile
+async fn prune<'a, C: ConnectionTrait<'a>>(
+    db: &'a C,
+    mut parent_reply_id: Option<i64>,
+) -> Result<(), DbErr> {
+    while let Some(reply_id) = parent_reply_id {
+        let parent = match reply::Entity::find_by_id(reply_id)
+            .filter(reply::Column::DeletedAt.is_not_null())
+            .lock_exclusive()
+            .one(db)
+            .await?
+        {
+            Some(parent) => parent,
+            None => break,
+        };
+
+        let has_children = reply::Entity::find()
+            .filter(reply::Column::ParentReplyId.eq(reply_id))
+            .one(db)
+            .await?
+            .is_some();
+        if has_children {
+            break;
+        }
+
+        parent_reply_id = parent.parent_reply_id;
+        parent.into_active_model().delete(db).await?;
+    }
+
+    Ok(())
+}
 
 // POST /post/{post_id}/reply
 // Takes in JSON encoded reply Input and user auth
+// With a parent_reply_id, the reply answers that reply instead of the post
 // On success, returns 200 OK with JSON encoded reply Output
+// If the parent reply is not on the post or is nested too deep, returns 400 Bad Request
 // If post_id does not exist, returns 404 Not Found
 pub async fn create(
     Json(input_reply): Json<reply::Input>,
@@ -24,24 +155,69 @@ pub async fn create(
     db: Data<DatabaseConnection>,
     token: token::Model,
 ) -> Result<Json<reply::Output>, InternalError<DbErr>> {
+    check_scope(&token, Scope::RepliesWrite)?;
+
     let post_id = param.into_inner();
 
-    let input_reply = input_reply.into_active_model();
-    let input_reply = reply::ActiveModel {
+    let parent = match input_reply.parent_reply_id {
+        Some(parent_reply_id) => Some(
+            reply::Entity::find_by_id(parent_reply_id)
+                .filter(reply::Column::PostId.eq(post_id))
+                .one(db.as_ref())
+                .await
+                .map_err(to_internal_error)?
+                .ok_or_else(|| {
+                    to_bad_request(DbErr::Custom(format!(
+                        "no reply {} on post {}",
+                        parent_reply_id, post_id
+                    )))
+                })?,
+        ),
+        None => None,
+    };
+
+    let depth = parent.as_ref().map_or(0, |parent| parent.depth + 1);
+    if depth > MAX_DEPTH {
+        return Err(to_bad_request(DbErr::Custom(format!(
+            "replies can't be nested more than {} deep",
+            MAX_DEPTH
+        ))));
+    }
+
+    let txn = db.begin().await.map_err(to_internal_error)?;
+
+    let reply = reply::ActiveModel {
         user_id: Set(token.user_id),
         post_id: Set(post_id),
+        parent_reply_id: Set(parent.as_ref().map(|parent| parent.id)),
+        depth: Set(depth),
+        path: Set(String::new()),
+        text: Set(input_reply.text),
         created_at: Set(Utc::now().naive_utc()),
-        ..input_reply
-    };
+        deleted_at: Set(None),
+        score: Set(0),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await
+    .map_err(to_internal_error)?;
 
-    let reply = input_reply
-        .insert(db.as_ref())
-        .await
-        .map_err(to_internal_error)?;
+    // The path ends in the reply's own id, only known once inserted
+    reply::ActiveModel {
+        id: Set(reply.id),
+        path: Set(match &parent {
+            Some(parent) => format!("{}/{}", parent.path, reply.id),
+            None => reply.id.to_string(),
+        }),
+        ..Default::default()
+    }
+    .update(&txn)
+    .await
+    .map_err(to_internal_error)?;
 
-    reply::Entity::find_by_id(reply.id)
-        .join(sea_orm::JoinType::InnerJoin, reply::Relation::User.def())
-        .column(user::Column::Username)
+    txn.commit().await.map_err(to_internal_error)?;
+
+    outputs(reply::Entity::find_by_id(reply.id), Some(token.user_id))
         .into_model::<reply::Output>()
         .one(db.as_ref())
         .await
@@ -53,62 +229,112 @@ pub async fn create(
 }
 
 // GET /post/{post_id}/reply/all
-// On success, returns 200 OK with JSON encoded reply Outputs
+// Takes in query encoded reply ListQuery and page PageQuery
+// Optionally takes in user auth, access tokens need the posts:read scope
+// On success, returns 200 OK with a JSON encoded Page of reply Outputs, oldest first, or
+// with view=tree a Page of reply Nodes, top-level replies oldest first
+// If a parameter or the cursor is invalid, returns 400 Bad Request
 // If post_id does not exist, returns 404 Not Found
 pub async fn read_all(
+    Query(list): Query<reply::ListQuery>,
+    Query(page): Query<PageQuery>,
     param: Path<i64>,
     db: Data<DatabaseConnection>,
-) -> Result<Json<Vec<reply::Output>>, InternalError<DbErr>> {
+    token: Option<token::Model>,
+) -> Result<HttpResponse, InternalError<DbErr>> {
+    if let Some(token) = &token {
+        check_scope(token, Scope::PostsRead)?;
+    }
+
     let post_id = param.into_inner();
 
-    reply::Entity::find()
-        .filter(reply::Column::PostId.eq(post_id))
-        .join(sea_orm::JoinType::InnerJoin, reply::Relation::User.def())
-        .column(user::Column::Username)
+    let mut query = reply::Entity::find().filter(reply::Column::PostId.eq(post_id));
+    if list.view == reply::View::Tree {
+        query = query.filter(reply::Column::ParentReplyId.is_null());
+    }
+    if let Some(cursor) = page_cursor(&page)? {
+        let created_at = match cursor.key {
+            CursorKey::Time(created_at) => created_at,
+            _ => return Err(invalid_cursor()),
+        };
+        query = query.filter(Expr::cust_with_values(
+            "(replies.created_at, replies.id) > (?, ?)",
+            vec![Value::from(created_at), Value::from(cursor.id)],
+        ));
+    }
+
+    let limit = page.limit();
+
+    let voter_id = token.map(|token| token.user_id);
+
+    let replies = outputs(query, voter_id)
+        .order_by_asc(reply::Column::CreatedAt)
+        .order_by_asc(reply::Column::Id)
+        .limit(limit + 1)
         .into_model::<reply::Output>()
         .all(db.as_ref())
         .await
-        .map(Json)
-        .map_err(to_not_found)
+        .map_err(to_not_found)?;
+
+    let page = Page::new(replies, limit, |reply| {
+        Cursor::time(reply.created_at, reply.id)
+    });
+
+    Ok(match list.view {
+        reply::View::Flat => HttpResponse::build(StatusCode::OK).json(page),
+        reply::View::Tree => HttpResponse::build(StatusCode::OK).json(Page {
+            items: tree(db.as_ref(), post_id, page.items, voter_id).await?,
+            next_cursor: page.next_cursor,
+        }),
+    })
 }
 
 // GET /post/{post_id}/reply/{reply_id}
+// Optionally takes in user auth, access tokens need the posts:read scope
 // On success, returns 200 OK with JSON encoded reply Output
 // If post_id, reply_id does not exist, returns 404 Not Found
 pub async fn read(
     param: Path<(i64, i64)>,
     db: Data<DatabaseConnection>,
+    token: Option<token::Model>,
 ) -> Result<Json<reply::Output>, InternalError<DbErr>> {
+    if let Some(token) = &token {
+        check_scope(token, Scope::PostsRead)?;
+    }
+
     let (post_id, reply_id) = param.into_inner();
 
-    reply::Entity::find_by_id(reply_id)
-        .filter(reply::Column::PostId.eq(post_id))
-        .join(sea_orm::JoinType::InnerJoin, reply::Relation::User.def())
-        .column(user::Column::Username)
-        .into_model::<reply::Output>()
-        .one(db.as_ref())
-        .await
-        .transpose()
-        .ok_or_else(|| DbErr::RecordNotFound(String::new()))
-        .and_then(std::convert::identity)
-        .map(Json)
-        .map_err(to_not_found)
+    outputs(
+        reply::Entity::find_by_id(reply_id).filter(reply::Column::PostId.eq(post_id)),
+        token.map(|token| token.user_id),
+    )
+    .into_model::<reply::Output>()
+    .one(db.as_ref())
+    .await
+    .transpose()
+    .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+    .and_then(std::convert::identity)
+    .map(Json)
+    .map_err(to_not_found)
 }
 
 // PATCH /post/{post_id}/reply/{reply_id}
-// Takes in JSON encoded reply Input and user auth
+// Takes in JSON encoded reply Update and user auth
 // On success, updates and returns 200 OK with JSON encoded reply Output
-// If post_id, reply_id does not exist, returns 404 Not Found
+// If post_id, reply_id does not exist or the reply was deleted, returns 404 Not Found
 pub async fn update(
-    Json(input_reply): Json<reply::Input>,
+    Json(input_reply): Json<reply::Update>,
     param: Path<(i64, i64)>,
     db: Data<DatabaseConnection>,
     token: token::Model,
 ) -> Result<Json<reply::Output>, InternalError<DbErr>> {
+    check_scope(&token, Scope::RepliesWrite)?;
+
     let (post_id, reply_id) = param.into_inner();
 
     let reply = reply::Entity::find_by_id(reply_id)
         .filter(reply::Column::PostId.eq(post_id))
+        .filter(reply::Column::DeletedAt.is_null())
         .one(db.as_ref())
         .await
         .transpose()
@@ -116,71 +342,125 @@ pub async fn update(
         .and_then(std::convert::identity)
         .map_err(to_not_found)?;
 
-    if reply.user_id != token.user_id {
-        return Err(InternalError::new(
-            DbErr::Custom("not real author".to_string()),
-            StatusCode::UNAUTHORIZED,
-        ));
-    }
+    let actor = actor(db.as_ref(), &token).await?;
+    let access = check_content(&actor, reply.user_id)?;
 
     let input_reply = input_reply.into_active_model();
     let input_reply = reply::ActiveModel {
         id: Set(reply_id),
         post_id: Set(post_id),
-        user_id: Set(token.user_id),
+        user_id: Set(reply.user_id),
         ..input_reply
     };
 
-    input_reply
-        .save(db.as_ref())
-        .await
-        .map_err(to_internal_error)?;
+    let txn = db.begin().await.map_err(to_internal_error)?;
 
-    reply::Entity::find_by_id(reply_id)
-        .filter(reply::Column::PostId.eq(post_id))
-        .join(sea_orm::JoinType::InnerJoin, reply::Relation::User.def())
-        .column(user::Column::Username)
-        .into_model::<reply::Output>()
-        .one(db.as_ref())
-        .await
-        .transpose()
-        .ok_or_else(|| DbErr::RecordNotFound(String::new()))
-        .and_then(std::convert::identity)
-        .map_err(to_internal_error)
-        .map(Json)
+    input_reply.save(&txn).await.map_err(to_internal_error)?;
+
+    if access == Access::Moderator {
+        log(
+            &txn,
+            &actor,
+            moderation::EDIT_REPLY,
+            reply.user_id,
+            Some(post_id),
+            Some(reply_id),
+            Some(reply.text),
+        )
+        .await?;
+    }
+
+    txn.commit().await.map_err(to_internal_error)?;
+
+    outputs(
+        reply::Entity::find_by_id(reply_id).filter(reply::Column::PostId.eq(post_id)),
+        Some(token.user_id),
+    )
+    .into_model::<reply::Output>()
+    .one(db.as_ref())
+    .await
+    .transpose()
+    .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+    .and_then(std::convert::identity)
+    .map_err(to_internal_error)
+    .map(Json)
 }
 
 // DELETE /post/{post_id}/reply/{reply_id}
 // Takes in user auth
 // On success, deletes and returns 200 OK
-// If post_id, reply_id does not exist, returns 404 Not Found
+// A reply with replies below it is kept as a placeholder without its text, and goes once
+// they are all deleted
+// If post_id, reply_id does not exist or the reply was deleted, returns 404 Not Found
 pub async fn delete(
     param: Path<(i64, i64)>,
     db: Data<DatabaseConnection>,
     token: token::Model,
 ) -> Result<HttpResponse, InternalError<DbErr>> {
+    check_scope(&token, Scope::RepliesWrite)?;
+
     let (post_id, reply_id) = param.into_inner();
 
+    let txn = db.begin().await.map_err(to_internal_error)?;
+
+    // Locked until the transaction ends, replies to it wait and then see it gone or placeheld
     let reply = reply::Entity::find_by_id(reply_id)
         .filter(reply::Column::PostId.eq(post_id))
-        .one(db.as_ref())
+        .filter(reply::Column::DeletedAt.is_null())
+        .lock_exclusive()
+        .one(&txn)
         .await
         .transpose()
         .ok_or_else(|| DbErr::RecordNotFound(String::new()))
         .and_then(std::convert::identity)
         .map_err(to_not_found)?;
 
-    if reply.user_id != token.user_id {
-        return Err(InternalError::new(
-            DbErr::Custom("not real author".to_string()),
-            StatusCode::UNAUTHORIZED,
-        ));
-    }
+    let actor = actor(db.as_ref(), &token).await?;
+    let access = check_content(&actor, reply.user_id)?;
+
+    let has_children = reply::Entity::find()
+        .filter(reply::Column::ParentReplyId.eq(reply.id))
+        .one(&txn)
+        .await
+        .map_err(to_internal_error)?
+        .is_some();
 
-    reply
-        .into_active_model()
-        .delete(db.as_ref())
+    if has_children {
+        reply::ActiveModel {
+            id: Set(reply.id),
+            text: Set(String::new()),
+            deleted_at: Set(Some(Utc::now().naive_utc())),
+            ..Default::default()
+        }
+        .update(&txn)
         .await
-        .map(to_ok)
-        .map_err(to_internal_error)
+        .map_err(to_internal_error)?;
+    } else {
+        reply
+            .clone()
+            .into_active_model()
+            .delete(&txn)
+            .await
+            .map_err(to_internal_error)?;
+        prune(&txn, reply.parent_reply_id)
+            .await
+            .map_err(to_internal_error)?;
+    }
+
+    if access == Access::Moderator {
+        log(
+            &txn,
+            &actor,
+            moderation::DELETE_REPLY,
+            reply.user_id,
+            Some(post_id),
+            Some(reply_id),
+            Some(reply.text),
+        )
+        .await?;
+    }
+
+    txn.commit().await.map_err(to_internal_error)?;
+
+    Ok(to_ok(()))
 }
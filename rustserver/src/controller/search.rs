@@ -0,0 +1,118 @@
+use actix_web::{
+    error::InternalError,
+    web::{Data, Json, Query},
+};
+use sea_orm::{DatabaseBackend, DatabaseConnection, DbErr, FromQueryResult, Statement, Value};
+
+use crate::model::{
+    page::{Cursor, CursorKey, Page, PageQuery},
+    search::{Hit, SearchQuery},
+    token::{self, Scope},
+};
+
+use super::{
+    invalid_cursor, page_cursor, permission::check_scope, to_bad_request, to_internal_error,
+};
+
+const MAX_QUERY_LEN: usize = 200;
+
+// Posts and replies matching the query in $1, best first, resuming after the cursor when
+// $2 is set, with $5 rows at most
+// Expressions on text match the GIN indexes in model::init, so the planner can use them
+const SEARCH_SQL: &str = "
+SELECT kind, id, post_id, title, user_id, username, rank, created_at,
+    ts_headline('english', replace(replace(replace(text, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'),
+        websearch_to_tsquery('english', $1),
+        'StartSel=<mark>, StopSel=</mark>, MaxFragments=2, MaxWords=30, MinWords=10') AS snippet
+FROM (
+    SELECT * FROM (
+        SELECT 'post' AS kind, posts.id, posts.id AS post_id, posts.title, posts.user_id,
+            users.username, concat_ws(' - ', posts.title, posts.summary, posts.text) AS text,
+            posts.created_at,
+            ts_rank(
+                setweight(to_tsvector('english', posts.title), 'A')
+                || setweight(to_tsvector('english', COALESCE(posts.summary, '')), 'B')
+                || setweight(to_tsvector('english', posts.text), 'C'),
+                websearch_to_tsquery('english', $1)
+            ) AS rank
+        FROM posts JOIN users ON users.id = posts.user_id
+        WHERE (
+                setweight(to_tsvector('english', posts.title), 'A')
+                || setweight(to_tsvector('english', COALESCE(posts.summary, '')), 'B')
+                || setweight(to_tsvector('english', posts.text), 'C')
+            ) @@ websearch_to_tsquery('english', $1)
+        UNION ALL
+        SELECT 'reply', replies.id, replies.post_id, posts.title, replies.user_id,
+            users.username, replies.text, replies.created_at,
+            ts_rank(to_tsvector('english', replies.text), websearch_to_tsquery('english', $1))
+        FROM replies
+            JOIN users ON users.id = replies.user_id
+            JOIN posts ON posts.id = replies.post_id
+        WHERE to_tsvector('english', replies.text) @@ websearch_to_tsquery('english', $1)
+    ) hits
+    WHERE $2::boolean IS NOT TRUE OR (rank, kind = 'reply', id) < ($3::real, $6::boolean, $4)
+    ORDER BY rank DESC, kind = 'reply' DESC, id DESC
+    LIMIT $5
+) page
+ORDER BY rank DESC, kind = 'reply' DESC, id DESC";
+
+// GET /search?q=
+// Takes in query encoded search SearchQuery and page PageQuery
+// Optionally takes in user auth, access tokens need the posts:read scope
+// On success, returns 200 OK with a JSON encoded Page of search Hits over posts and
+// replies, best match first
+// If q is empty or too long, or the cursor is invalid, returns 400 Bad Request
+// On error, returns 500 Internal Server Error
+pub async fn search(
+    Query(search): Query<SearchQuery>,
+    Query(page): Query<PageQuery>,
+    db: Data<DatabaseConnection>,
+    token: Option<token::Model>,
+) -> Result<Json<Page<Hit>>, InternalError<DbErr>> {
+    if let Some(token) = &token {
+        check_scope(token, Scope::PostsRead)?;
+    }
+
+    let q = search.q.trim();
+    if q.is_empty() || q.chars().count() > MAX_QUERY_LEN {
+        return Err(to_bad_request(DbErr::Custom(format!(
+            "q must be 1 to {} characters",
+            MAX_QUERY_LEN
+        ))));
+    }
+
+    let (after, rank, reply, id) = match page_cursor(&page)? {
+        Some(Cursor {
+            key: CursorKey::Rank(rank, reply),
+            id,
+        }) => (true, rank, reply, id),
+        Some(_) => return Err(invalid_cursor()),
+        None => (false, 0.0, false, 0),
+    };
+
+    let limit = page.limit();
+
+    let stmt = Statement::from_sql_and_values(
+        DatabaseBackend::Postgres,
+        SEARCH_SQL,
+        vec![
+            Value::from(q),
+            Value::from(after),
+            Value::from(rank),
+            Value::from(id),
+            Value::from((limit + 1) as i64),
+            Value::from(reply),
+        ],
+    );
+
+    Hit::find_by_statement(stmt)
+        .all(db.as_ref())
+        .await
+        .map(|hits| {
+            Json(Page::new(hits, limit, |hit| Cursor {
+                key: CursorKey::Rank(hit.rank, hit.kind == "reply"),
+                id: hit.id,
+            }))
+        })
+        .map_err(to_internal_error)
+}
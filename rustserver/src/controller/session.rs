@@ -0,0 +1,66 @@
+use actix_web::{
+    error::InternalError,
+    web::{Data, Json, Path},
+    HttpResponse,
+};
+use chrono::Utc;
+use sea_orm::{ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder};
+
+use crate::model::token;
+
+use super::{permission::check_session, to_internal_error, to_not_found, to_ok};
+
+// GET /sessions
+// Takes in user auth
+// On success, returns 200 OK with JSON encoded token Sessions of the user
+// On error, returns 500 Internal Server Error
+pub async fn read_all(
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<Json<Vec<token::Session>>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    token::Entity::find()
+        .filter(token::Column::UserId.eq(token.user_id))
+        .filter(token::Column::Scopes.is_null())
+        .filter(token::Column::ExpiresAt.gt(Utc::now().naive_utc()))
+        .order_by_desc(token::Column::LastUsedAt)
+        .all(db.as_ref())
+        .await
+        .map(|tokens| {
+            tokens
+                .iter()
+                .map(|t| t.to_session(t.id == token.id))
+                .collect()
+        })
+        .map(Json)
+        .map_err(to_internal_error)
+}
+
+// DELETE /sessions/{session_id}
+// Takes in user auth
+// On success, revokes the session and returns 200 OK
+// If session_id does not exist for the user, returns 404 Not Found
+pub async fn delete(
+    param: Path<i64>,
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<HttpResponse, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let session_id = param.into_inner();
+
+    let res = token::Entity::delete_many()
+        .filter(token::Column::Id.eq(session_id))
+        .filter(token::Column::UserId.eq(token.user_id))
+        .filter(token::Column::Scopes.is_null())
+        .exec(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    if res.rows_affected == 0 {
+        return Err(to_not_found(DbErr::RecordNotFound(String::new())));
+    }
+
+    Ok(to_ok(res))
+}
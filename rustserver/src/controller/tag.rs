@@ -0,0 +1,191 @@
+use actix_web::{
+    error::InternalError,
+    web::{Data, Json, Path, Query},
+};
+use chrono::Utc;
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, ConnectionTrait, DatabaseBackend, DatabaseConnection, DbErr,
+    EntityTrait, JoinType, Order, QueryFilter, QueryOrder, QuerySelect, RelationTrait, Statement,
+    Value,
+};
+
+use crate::model::{
+    page::{Page, PageQuery},
+    post, post_tag, tag,
+    token::{self, Scope},
+};
+
+use super::{
+    permission::check_scope, route_post::list_posts, to_bad_request, to_internal_error,
+    to_not_found,
+};
+
+const MAX_TAGS: usize = 5;
+const MAX_TAG_LEN: usize = 32;
+
+// Lowercases a tag, drops a leading '#' and joins words with dashes
+// If the tag is empty, too long or has other than letters, digits, '-' and '.', fails with
+// 400 Bad Request
+pub fn normalise(value: &str) -> Result<String, InternalError<DbErr>> {
+    let value = value
+        .trim()
+        .trim_start_matches('#')
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '_')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+
+    let valid = (1..=MAX_TAG_LEN).contains(&value.chars().count())
+        && value
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '.');
+
+    if valid {
+        Ok(value)
+    } else {
+        Err(to_bad_request(DbErr::Custom(format!(
+            "tags must be up to {} letters, digits, '-' and '.'",
+            MAX_TAG_LEN
+        ))))
+    }
+}
+
+// Normalises the tags of a post, dropping duplicates
+// If a tag is invalid or there are too many, fails with 400 Bad Request
+pub fn normalise_all(values: &[String]) -> Result<Vec<String>, InternalError<DbErr>> {
+    let mut tags = Vec::new();
+    for value in values {
+        let tag = normalise(value)?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    if tags.len() > MAX_TAGS {
+        return Err(to_bad_request(DbErr::Custom(format!(
+            "a post can have at most {} tags",
+            MAX_TAGS
+        ))));
+    }
+    Ok(tags)
+}
+
+// Normalises the comma separated tags a listing is filtered by
+// If a tag is invalid or there are too many, fails with 400 Bad Request
+pub fn normalise_filter(value: &str) -> Result<Vec<String>, InternalError<DbErr>> {
+    let values: Vec<&str> = value.split(',').collect();
+    if values.len() > MAX_TAGS {
+        return Err(to_bad_request(DbErr::Custom(format!(
+            "at most {} tags can be filtered by",
+            MAX_TAGS
+        ))));
+    }
+
+    values.into_iter().map(normalise).collect()
+}
+
+// Replaces the tags of a post with the normalised tags, creating the ones not seen before
+pub async fn set_tags<'a, C: ConnectionTrait<'a>>(
+    db: &'a C,
+    post_id: i64,
+    tags: &[String],
+) -> Result<(), DbErr> {
+    post_tag::Entity::delete_many()
+        .filter(post_tag::Column::PostId.eq(post_id))
+        .exec(db)
+        .await?;
+
+    for name in tags {
+        db.execute(Statement::from_sql_and_values(
+            DatabaseBackend::Postgres,
+            "INSERT INTO tags (name, created_at) VALUES ($1, $2) ON CONFLICT (name) DO NOTHING",
+            vec![
+                Value::from(name.as_str()),
+                Value::from(Utc::now().naive_utc()),
+            ],
+        ))
+        .await?;
+
+        db.execute(Statement::from_sql_and_values(
+            DatabaseBackend::Postgres,
+            "INSERT INTO post_tags (post_id, tag_id) SELECT $1, id FROM tags WHERE name = $2",
+            vec![Value::from(post_id), Value::from(name.as_str())],
+        ))
+        .await?;
+    }
+
+    Ok(())
+}
+
+// Finds the names of a post's tags, in alphabetical order
+pub async fn tags_of(db: &DatabaseConnection, post_id: i64) -> Result<Vec<String>, DbErr> {
+    tag::Entity::find()
+        .join(JoinType::InnerJoin, tag::Relation::PostTag.def())
+        .filter(post_tag::Column::PostId.eq(post_id))
+        .order_by_asc(tag::Column::Name)
+        .all(db)
+        .await
+        .map(|tags| tags.into_iter().map(|tag| tag.name).collect())
+}
+
+// GET /tags
+// On success, returns 200 OK with JSON encoded tag Outputs of the tags in use, most used
+// first
+pub async fn read_all(
+    db: Data<DatabaseConnection>,
+) -> Result<Json<Vec<tag::Output>>, InternalError<DbErr>> {
+    tag::Entity::find()
+        .select_only()
+        .column(tag::Column::Name)
+        .column_as(Expr::cust("COUNT(*)"), "post_count")
+        .join(JoinType::InnerJoin, tag::Relation::PostTag.def())
+        .group_by(tag::Column::Id)
+        .order_by(Expr::cust("post_count"), Order::Desc)
+        .order_by_asc(tag::Column::Name)
+        .into_model::<tag::Output>()
+        .all(db.as_ref())
+        .await
+        .map(Json)
+        .map_err(to_internal_error)
+}
+
+// GET /tag/{name}/posts
+// Takes in query encoded post ListQuery and page PageQuery, as GET /post/all
+// Optionally takes in user auth, access tokens need the posts:read scope
+// On success, returns 200 OK with a JSON encoded Page of the tagged post Outputs
+// If a parameter, the cursor or the tag is invalid, returns 400 Bad Request
+// If the tag does not exist, returns 404 Not Found
+pub async fn posts(
+    Query(list): Query<post::ListQuery>,
+    Query(page): Query<PageQuery>,
+    param: Path<String>,
+    db: Data<DatabaseConnection>,
+    token: Option<token::Model>,
+) -> Result<Json<Page<post::Output>>, InternalError<DbErr>> {
+    if let Some(token) = &token {
+        check_scope(token, Scope::PostsRead)?;
+    }
+
+    let name = normalise(&param.into_inner())?;
+
+    let tag = tag::Entity::find()
+        .filter(tag::Column::Name.eq(name))
+        .one(db.as_ref())
+        .await
+        .map_err(to_internal_error)?
+        .ok_or_else(|| to_not_found(DbErr::RecordNotFound(String::new())))?;
+
+    list_posts(
+        db.as_ref(),
+        post::Entity::find().filter(Expr::cust_with_values(
+            "posts.id IN (SELECT post_id FROM post_tags WHERE tag_id = ?)",
+            vec![tag.id],
+        )),
+        list,
+        page,
+        token.map(|token| token.user_id),
+    )
+    .await
+    .map(Json)
+}
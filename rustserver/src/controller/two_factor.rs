@@ -0,0 +1,456 @@
+use actix_web::{
+    error::InternalError,
+    http::{header::RETRY_AFTER, StatusCode},
+    web::{Data, Json},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use chrono::Utc;
+use sea_orm::{
+    prelude::Uuid, sea_query::Expr, ActiveModelTrait, ColumnTrait, Condition, DatabaseConnection,
+    DbErr, EntityTrait, QueryFilter, Set,
+};
+
+use crate::{
+    config::Config,
+    crypto::{
+        code_digest, decode_totp_secret, generate_recovery_code, generate_totp_secret,
+        token_digest, totp_uri, verify_totp, NO_PASSWORD,
+    },
+    model::{
+        action_token, audit as audit_event, recovery_code, token,
+        user::{self, LoginResponse},
+    },
+};
+
+use super::{
+    audit,
+    auth::issue_token,
+    client_ip, lockout,
+    permission::{actor, check_session},
+    to_internal_error, verify_blocking,
+};
+
+const ISSUER: &str = "aeroFans";
+const RECOVERY_CODES: usize = 10;
+// Codes a login challenge may be tried with before it is thrown away
+const MAX_CHALLENGE_ATTEMPTS: i32 = 3;
+
+fn invalid_password() -> InternalError<DbErr> {
+    InternalError::new(
+        DbErr::Custom("invalid password or two-factor code".to_string()),
+        StatusCode::UNAUTHORIZED,
+    )
+}
+
+// Checks the user confirmed a change to their two-factor authentication
+// Accounts that only sign in through SSO have no password, once two-factor authentication is
+// enabled they confirm with a TOTP or recovery code instead
+async fn confirms(
+    db: &DatabaseConnection,
+    config: &Config,
+    user: &user::Model,
+    input: &user::PasswordConfirm,
+) -> Result<bool, InternalError<DbErr>> {
+    if user.password != NO_PASSWORD {
+        return verify_blocking(&input.password, &user.password).await;
+    }
+    if !user.totp_enabled {
+        return Ok(true);
+    }
+    let code = match &input.code {
+        Some(code) => code,
+        None => return Ok(false),
+    };
+
+    // Codes are guessed against the same lockout as logins
+    let keys = lockout::keys(config, &user.username, None);
+    if lockout::locked_until(db, &keys)
+        .await
+        .map_err(to_internal_error)?
+        .is_some()
+    {
+        return Ok(false);
+    }
+
+    let verified = verify_second_factor(db, user, code)
+        .await
+        .map_err(to_internal_error)?;
+    if !verified {
+        lockout::record_failure(db, config, &keys)
+            .await
+            .map_err(to_internal_error)?;
+    }
+    Ok(verified)
+}
+
+fn conflict(message: &str) -> InternalError<DbErr> {
+    InternalError::new(DbErr::Custom(message.to_string()), StatusCode::CONFLICT)
+}
+
+// POST /me/2fa
+// Takes in JSON encoded user PasswordConfirm and session auth
+// SSO-only accounts need no password, they have no second factor to confirm with yet
+// On success, starts enrolment and returns 200 OK with JSON encoded user TwoFactorEnrolment
+// Enrolment takes effect once confirmed with POST /me/2fa/confirm
+// If the password is wrong, returns 401 Unauthorized
+// If two-factor authentication is already enabled, returns 409 Conflict
+pub async fn enrol(
+    Json(input): Json<user::PasswordConfirm>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<Json<user::TwoFactorEnrolment>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let user = actor(db.as_ref(), &token).await?;
+
+    if !confirms(db.as_ref(), &config, &user, &input).await? {
+        return Err(invalid_password());
+    }
+    if user.totp_enabled {
+        return Err(conflict("two-factor authentication is already enabled"));
+    }
+
+    let secret = generate_totp_secret();
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| generate_recovery_code())
+        .collect();
+
+    user::ActiveModel {
+        id: Set(user.id),
+        totp_secret: Set(Some(secret.clone())),
+        totp_enabled: Set(false),
+        totp_last_step: Set(None),
+        ..Default::default()
+    }
+    .update(db.as_ref())
+    .await
+    .map_err(to_internal_error)?;
+
+    recovery_code::Entity::delete_many()
+        .filter(recovery_code::Column::UserId.eq(user.id))
+        .exec(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    recovery_code::Entity::insert_many(recovery_codes.iter().map(|code| {
+        recovery_code::ActiveModel {
+            user_id: Set(user.id),
+            hash: Set(code_digest(code.as_bytes())),
+            ..Default::default()
+        }
+    }))
+    .exec(db.as_ref())
+    .await
+    .map_err(to_internal_error)?;
+
+    Ok(Json(user::TwoFactorEnrolment {
+        otpauth_uri: totp_uri(ISSUER, &user.username, &secret),
+        secret,
+        recovery_codes,
+    }))
+}
+
+// POST /me/2fa/confirm
+// Takes in JSON encoded user TwoFactorCode and session auth
+// On success, enables two-factor authentication and returns 200 OK with JSON encoded
+// LoginResponse
+// If no enrolment is pending, returns 409 Conflict
+pub async fn confirm(
+    Json(input): Json<user::TwoFactorCode>,
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<Json<LoginResponse>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let user = actor(db.as_ref(), &token).await?;
+
+    let secret = match (&user.totp_secret, user.totp_enabled) {
+        (Some(secret), false) => decode_totp_secret(secret).unwrap_or_default(),
+        _ => return Err(conflict("no two-factor enrolment is pending")),
+    };
+
+    let step = match verify_totp(&secret, &input.code, None) {
+        Some(step) => step,
+        None => {
+            return Ok(Json(LoginResponse {
+                status: false,
+                message: "invalid two-factor code",
+            }))
+        }
+    };
+
+    user::ActiveModel {
+        id: Set(user.id),
+        totp_enabled: Set(true),
+        totp_last_step: Set(Some(step)),
+        ..Default::default()
+    }
+    .update(db.as_ref())
+    .await
+    .map_err(to_internal_error)?;
+
+    Ok(Json(LoginResponse {
+        status: true,
+        message: "two-factor authentication enabled",
+    }))
+}
+
+// DELETE /me/2fa
+// Takes in JSON encoded user PasswordConfirm and session auth
+// SSO-only accounts confirm with a TOTP or recovery code in place of the password
+// On success, disables two-factor authentication and returns 200 OK with JSON encoded
+// LoginResponse
+// If the password or code is wrong, returns 401 Unauthorized
+pub async fn disable(
+    Json(input): Json<user::PasswordConfirm>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<Json<LoginResponse>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let user = actor(db.as_ref(), &token).await?;
+
+    if !confirms(db.as_ref(), &config, &user, &input).await? {
+        return Err(invalid_password());
+    }
+
+    user::ActiveModel {
+        id: Set(user.id),
+        totp_secret: Set(None),
+        totp_enabled: Set(false),
+        totp_last_step: Set(None),
+        ..Default::default()
+    }
+    .update(db.as_ref())
+    .await
+    .map_err(to_internal_error)?;
+
+    recovery_code::Entity::delete_many()
+        .filter(recovery_code::Column::UserId.eq(user.id))
+        .exec(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    Ok(Json(LoginResponse {
+        status: true,
+        message: "two-factor authentication disabled",
+    }))
+}
+
+// Starts the second step of a login for a user with two-factor authentication
+// Returns 200 OK with JSON encoded user ChallengeResponse
+pub async fn challenge(db: &DatabaseConnection, config: &Config, user_id: i64) -> HttpResponse {
+    match create_challenge(db, config, user_id).await {
+        Ok(uuid) => HttpResponse::build(StatusCode::OK).json(user::ChallengeResponse {
+            status: false,
+            message: "two-factor code required",
+            challenge: uuid.to_string(),
+        }),
+        Err(e) => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).body(e.to_string()),
+    }
+}
+
+// Stores a login challenge for the user, returning its id
+pub async fn create_challenge(
+    db: &DatabaseConnection,
+    config: &Config,
+    user_id: i64,
+) -> Result<Uuid, DbErr> {
+    let uuid = Uuid::new_v4();
+    let now = Utc::now();
+
+    action_token::ActiveModel {
+        hash: Set(token_digest(&config.token_secret, uuid.as_bytes())),
+        user_id: Set(user_id),
+        purpose: Set(action_token::LOGIN_CHALLENGE.to_owned()),
+        expires_at: Set((now + config.login_challenge_lifetime).naive_utc()),
+        attempts: Set(0),
+        created_at: Set(now.naive_utc()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await
+    .map(|_| uuid)
+}
+
+// POST /login/2fa
+// Takes in JSON encoded user ChallengeInput, with a TOTP or recovery code
+// On success, returns 200 OK with JSON encoded LoginResponse and cookie
+// On a bad code or challenge, returns 200 OK with JSON encoded LoginResponse
+// A challenge is thrown away after MAX_CHALLENGE_ATTEMPTS wrong codes, needing a new login
+// While the username or client IP is locked out, returns 429 Too Many Requests
+// On error, returns 500 Internal Server Error
+pub async fn login(
+    Json(input): Json<user::ChallengeInput>,
+    req: HttpRequest,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+) -> HttpResponse {
+    let failed = || {
+        HttpResponse::build(StatusCode::OK).json(LoginResponse {
+            status: false,
+            message: "invalid two-factor code",
+        })
+    };
+
+    let uuid = match Uuid::parse_str(&input.challenge) {
+        Ok(uuid) => uuid,
+        Err(_) => return failed(),
+    };
+
+    let challenge = match action_token::Entity::find()
+        .filter(action_token::Column::Hash.eq(token_digest(&config.token_secret, uuid.as_bytes())))
+        .filter(action_token::Column::Purpose.eq(action_token::LOGIN_CHALLENGE))
+        .filter(action_token::Column::ExpiresAt.gt(Utc::now().naive_utc()))
+        .one(db.get_ref())
+        .await
+    {
+        Ok(Some(challenge)) => challenge,
+        _ => return failed(),
+    };
+
+    let user = match user::Entity::find_by_id(challenge.user_id)
+        .one(db.get_ref())
+        .await
+    {
+        Ok(Some(user)) => user,
+        _ => return failed(),
+    };
+
+    let ip = client_ip(&req);
+    let keys = lockout::keys(&config, &user.username, ip.as_deref());
+
+    let locked_until = match lockout::locked_until(db.get_ref(), &keys).await {
+        Ok(locked_until) => locked_until,
+        Err(e) => return to_internal_error(e).error_response(),
+    };
+    if let Some(until) = locked_until {
+        audit::record(
+            db.get_ref(),
+            &req,
+            audit_event::LOGIN_LOCKED,
+            Some(user.id),
+            Some(&user.username),
+            Some("two-factor"),
+        )
+        .await;
+        return HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+            .insert_header((RETRY_AFTER, lockout::retry_after(until).to_string()))
+            .json(LoginResponse {
+                status: false,
+                message: "too many failed logins, try again later",
+            });
+    }
+
+    // Claim an attempt before checking the code, so parallel guesses can't exceed the cap
+    let claimed = action_token::Entity::update_many()
+        .col_expr(
+            action_token::Column::Attempts,
+            Expr::col(action_token::Column::Attempts).add(1),
+        )
+        .filter(action_token::Column::Id.eq(challenge.id))
+        .filter(action_token::Column::Attempts.lt(MAX_CHALLENGE_ATTEMPTS))
+        .exec(db.get_ref())
+        .await;
+    if !matches!(claimed, Ok(res) if res.rows_affected == 1) {
+        return failed();
+    }
+
+    if !matches!(
+        verify_second_factor(db.get_ref(), &user, &input.code).await,
+        Ok(true)
+    ) {
+        let _ = lockout::record_failure(db.get_ref(), &config, &keys).await;
+        let _ = action_token::Entity::delete_many()
+            .filter(action_token::Column::Id.eq(challenge.id))
+            .filter(action_token::Column::Attempts.gte(MAX_CHALLENGE_ATTEMPTS))
+            .exec(db.get_ref())
+            .await;
+        audit::record(
+            db.get_ref(),
+            &req,
+            audit_event::LOGIN_FAILURE,
+            Some(user.id),
+            Some(&user.username),
+            Some("wrong two-factor code"),
+        )
+        .await;
+        return failed();
+    }
+
+    // The challenge is spent
+    let _ = action_token::Entity::delete_many()
+        .filter(action_token::Column::Id.eq(challenge.id))
+        .exec(db.get_ref())
+        .await;
+
+    let _ = lockout::record_success(db.get_ref(), &user.username).await;
+
+    if let Some(message) = user.status_error(Utc::now().naive_utc()) {
+        audit::record(
+            db.get_ref(),
+            &req,
+            audit_event::LOGIN_FAILURE,
+            Some(user.id),
+            Some(&user.username),
+            Some(message),
+        )
+        .await;
+        return HttpResponse::build(StatusCode::FORBIDDEN).json(LoginResponse {
+            status: false,
+            message,
+        });
+    }
+
+    audit::record(
+        db.get_ref(),
+        &req,
+        audit_event::LOGIN_SUCCESS,
+        Some(user.id),
+        Some(&user.username),
+        Some("two-factor"),
+    )
+    .await;
+
+    issue_token(&req, db.get_ref(), &config, user.id).await
+}
+
+// Checks a TOTP code, or else consumes a matching recovery code
+async fn verify_second_factor(
+    db: &DatabaseConnection,
+    user: &user::Model,
+    code: &str,
+) -> Result<bool, DbErr> {
+    let secret = user
+        .totp_secret
+        .as_deref()
+        .and_then(decode_totp_secret)
+        .unwrap_or_default();
+
+    if let Some(step) = verify_totp(&secret, code, user.totp_last_step) {
+        // Claim the step, losing a race with a concurrent login means the code was replayed
+        let res = user::Entity::update_many()
+            .col_expr(user::Column::TotpLastStep, Expr::value(Some(step)))
+            .filter(user::Column::Id.eq(user.id))
+            .filter(
+                Condition::any()
+                    .add(user::Column::TotpLastStep.is_null())
+                    .add(user::Column::TotpLastStep.lt(step)),
+            )
+            .exec(db)
+            .await?;
+
+        return Ok(res.rows_affected == 1);
+    }
+
+    let code = code.trim().to_lowercase();
+    let res = recovery_code::Entity::delete_many()
+        .filter(recovery_code::Column::UserId.eq(user.id))
+        .filter(recovery_code::Column::Hash.eq(code_digest(code.as_bytes())))
+        .exec(db)
+        .await?;
+
+    Ok(res.rows_affected == 1)
+}
@@ -0,0 +1,366 @@
+use actix_web::{
+    error::InternalError,
+    http::StatusCode,
+    web::{Data, Json, Path},
+};
+use sea_orm::{
+    sea_query::Expr, ActiveEnum, ActiveModelTrait, ColumnTrait, ConnectionTrait,
+    DatabaseConnection, DbErr, EntityTrait, NotSet, QueryFilter, QueryOrder, QuerySelect, Select,
+    Set,
+};
+
+use crate::{
+    config::Config,
+    model::{
+        moderation, token,
+        user::{self, Status},
+    },
+};
+
+use super::{
+    permission::{actor, check_admin, check_moderator, check_session, log},
+    to_bad_request, to_internal_error, to_not_found,
+};
+
+const MAX_DISPLAY_NAME_LEN: usize = 50;
+const MAX_BIO_LEN: usize = 1000;
+
+// Selects user Profiles, with post and reply counts
+fn profiles() -> Select<user::Entity> {
+    user::Entity::find()
+        .select_only()
+        .column(user::Column::Id)
+        .column(user::Column::Username)
+        .column(user::Column::Email)
+        .column(user::Column::DisplayName)
+        .column(user::Column::Bio)
+        .column(user::Column::Role)
+        .column(user::Column::Status)
+        .column(user::Column::SuspendedUntil)
+        .column(user::Column::CreatedAt)
+        .column_as(
+            Expr::cust(
+                "(SELECT inviter.username FROM users inviter WHERE inviter.id = users.invited_by)",
+            ),
+            "invited_by",
+        )
+        .column_as(
+            Expr::cust("(SELECT COUNT(*) FROM posts WHERE posts.user_id = users.id)"),
+            "post_count",
+        )
+        .column_as(
+            Expr::cust("(SELECT COUNT(*) FROM replies WHERE replies.user_id = users.id)"),
+            "reply_count",
+        )
+}
+
+async fn read_profile(
+    db: &DatabaseConnection,
+    query: Select<user::Entity>,
+) -> Result<user::Profile, InternalError<DbErr>> {
+    query
+        .into_model::<user::Profile>()
+        .one(db)
+        .await
+        .transpose()
+        .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+        .and_then(std::convert::identity)
+        .map_err(to_not_found)
+}
+
+// GET /me
+// Takes in user auth
+// On success, returns 200 OK with JSON encoded user Profile including email
+pub async fn me(
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<Json<user::Profile>, InternalError<DbErr>> {
+    read_profile(
+        db.as_ref(),
+        profiles().filter(user::Column::Id.eq(token.user_id)),
+    )
+    .await
+    .map(Json)
+}
+
+// GET /user/{username}
+// On success, returns 200 OK with JSON encoded user Profile, without email and inviter
+// If username does not exist, returns 404 Not Found
+pub async fn read(
+    param: Path<String>,
+    db: Data<DatabaseConnection>,
+) -> Result<Json<user::Profile>, InternalError<DbErr>> {
+    let username = param.into_inner();
+
+    let mut profile = read_profile(
+        db.as_ref(),
+        profiles().filter(user::Column::Username.eq(username)),
+    )
+    .await?;
+
+    profile.email = None;
+    profile.invited_by = None;
+
+    Ok(Json(profile))
+}
+
+// PATCH /me
+// Takes in JSON encoded user ProfileUpdate and user auth
+// Omitted fields are left alone, empty strings clear them
+// On success, updates and returns 200 OK with JSON encoded user Profile
+// If a field is too long, returns 400 Bad Request
+pub async fn update(
+    Json(input): Json<user::ProfileUpdate>,
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<Json<user::Profile>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let field = |value: Option<String>, max_len: usize, name: &str| match value {
+        None => Ok(NotSet),
+        Some(v) if v.chars().count() > max_len => Err(DbErr::Custom(format!(
+            "{} is longer than {} characters",
+            name, max_len
+        ))),
+        Some(v) => {
+            let v = v.trim().to_owned();
+            Ok(Set(Some(v).filter(|v| !v.is_empty())))
+        }
+    };
+
+    let update = user::ActiveModel {
+        id: Set(token.user_id),
+        display_name: field(input.display_name, MAX_DISPLAY_NAME_LEN, "display_name")
+            .map_err(to_bad_request)?,
+        bio: field(input.bio, MAX_BIO_LEN, "bio").map_err(to_bad_request)?,
+        ..Default::default()
+    };
+
+    if update.display_name.is_set() || update.bio.is_set() {
+        update
+            .update(db.as_ref())
+            .await
+            .map_err(to_internal_error)?;
+    }
+
+    read_profile(
+        db.as_ref(),
+        profiles().filter(user::Column::Id.eq(token.user_id)),
+    )
+    .await
+    .map(Json)
+}
+
+// PUT /user/{username}/role
+// Takes in JSON encoded user RoleUpdate and admin auth
+// On success, changes the role and returns 200 OK with JSON encoded user Profile
+// If the caller is not an admin, returns 403 Forbidden
+// If username does not exist, returns 404 Not Found
+pub async fn update_role(
+    Json(input): Json<user::RoleUpdate>,
+    param: Path<String>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<Json<user::Profile>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let username = param.into_inner();
+
+    let actor = actor(db.as_ref(), &token).await?;
+    check_admin(&actor, &config)?;
+
+    let target = user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(db.as_ref())
+        .await
+        .transpose()
+        .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+        .and_then(std::convert::identity)
+        .map_err(to_not_found)?;
+
+    let txn = db.begin().await.map_err(to_internal_error)?;
+
+    user::ActiveModel {
+        id: Set(target.id),
+        role: Set(input.role),
+        ..Default::default()
+    }
+    .update(&txn)
+    .await
+    .map_err(to_internal_error)?;
+
+    log(
+        &txn,
+        &actor,
+        moderation::SET_ROLE,
+        target.id,
+        None,
+        None,
+        Some(format!(
+            "{} -> {}",
+            target.role.to_value(),
+            input.role.to_value()
+        )),
+    )
+    .await?;
+
+    txn.commit().await.map_err(to_internal_error)?;
+
+    read_profile(
+        db.as_ref(),
+        profiles().filter(user::Column::Id.eq(target.id)),
+    )
+    .await
+    .map(Json)
+}
+
+// PUT /user/{username}/status
+// Takes in JSON encoded user StatusUpdate and moderator auth
+// Moderators may suspend users and lift suspensions, banning, unbanning and marking accounts
+// pending verification takes an admin
+// On success, changes the status and returns 200 OK with JSON encoded user Profile
+// If the caller may not change the account, returns 403 Forbidden
+// If username does not exist, returns 404 Not Found
+pub async fn update_status(
+    Json(input): Json<user::StatusUpdate>,
+    param: Path<String>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    token: token::Model,
+) -> Result<Json<user::Profile>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let username = param.into_inner();
+
+    let actor = actor(db.as_ref(), &token).await?;
+    check_moderator(&actor)?;
+
+    let target = user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(db.as_ref())
+        .await
+        .transpose()
+        .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+        .and_then(std::convert::identity)
+        .map_err(to_not_found)?;
+
+    // Staff can only act on accounts ranking below them
+    if target.role >= actor.role {
+        return Err(InternalError::new(
+            DbErr::Custom("cannot change the status of this account".to_string()),
+            StatusCode::FORBIDDEN,
+        ));
+    }
+
+    let moderator_change = matches!(input.status, Status::Active | Status::Suspended)
+        && matches!(target.status, Status::Active | Status::Suspended);
+    if !moderator_change {
+        check_admin(&actor, &config)?;
+    }
+
+    let suspended_until = input
+        .suspended_until
+        .filter(|_| input.status == Status::Suspended);
+
+    let mut detail = format!(
+        "{} -> {}",
+        target.status.to_value(),
+        input.status.to_value()
+    );
+    if let Some(until) = suspended_until {
+        detail.push_str(&format!(" until {}", until));
+    }
+    if let Some(reason) = input.reason.filter(|r| !r.trim().is_empty()) {
+        detail.push_str(&format!(": {}", reason.trim()));
+    }
+
+    let txn = db.begin().await.map_err(to_internal_error)?;
+
+    user::ActiveModel {
+        id: Set(target.id),
+        status: Set(input.status),
+        suspended_until: Set(suspended_until),
+        ..Default::default()
+    }
+    .update(&txn)
+    .await
+    .map_err(to_internal_error)?;
+
+    log(
+        &txn,
+        &actor,
+        moderation::SET_STATUS,
+        target.id,
+        None,
+        None,
+        Some(detail),
+    )
+    .await?;
+
+    txn.commit().await.map_err(to_internal_error)?;
+
+    read_profile(
+        db.as_ref(),
+        profiles().filter(user::Column::Id.eq(target.id)),
+    )
+    .await
+    .map(Json)
+}
+
+// GET /user/{username}/invitees
+// Takes in moderator auth
+// On success, returns 200 OK with JSON encoded user Profiles of everyone the user invited
+// If the caller is not a moderator, returns 403 Forbidden
+// If username does not exist, returns 404 Not Found
+pub async fn invitees(
+    param: Path<String>,
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<Json<Vec<user::Profile>>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let username = param.into_inner();
+
+    let actor = actor(db.as_ref(), &token).await?;
+    check_moderator(&actor)?;
+
+    let inviter = user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(db.as_ref())
+        .await
+        .transpose()
+        .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+        .and_then(std::convert::identity)
+        .map_err(to_not_found)?;
+
+    profiles()
+        .filter(user::Column::InvitedBy.eq(inviter.id))
+        .order_by_asc(user::Column::Id)
+        .into_model::<user::Profile>()
+        .all(db.as_ref())
+        .await
+        .map(Json)
+        .map_err(to_internal_error)
+}
+
+// GET /moderation/log
+// Takes in moderator auth
+// On success, returns 200 OK with JSON encoded moderation log entries, newest first
+// If the caller is not a moderator, returns 403 Forbidden
+pub async fn moderation_log(
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<Json<Vec<moderation::Model>>, InternalError<DbErr>> {
+    check_session(&token)?;
+
+    let actor = actor(db.as_ref(), &token).await?;
+    check_moderator(&actor)?;
+
+    moderation::Entity::find()
+        .order_by_desc(moderation::Column::Id)
+        .all(db.as_ref())
+        .await
+        .map(Json)
+        .map_err(to_internal_error)
+}
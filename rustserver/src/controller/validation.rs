@@ -0,0 +1,258 @@
+use crate::{config::Config, model::user::FieldError};
+
+// Longest password accepted, keeping hashing cheap
+const MAX_PASSWORD_LEN: usize = 1024;
+const MAX_EMAIL_LEN: usize = 254;
+
+fn error(field: &'static str, code: &'static str, message: String) -> FieldError {
+    FieldError {
+        field,
+        code,
+        message,
+    }
+}
+
+// Checks a new username against the configured length, charset and reserved names
+// Uniqueness needs the database and is checked by the caller
+pub fn username(config: &Config, username: &str) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    let len = username.chars().count();
+
+    if len < config.username_min_len || len > config.username_max_len {
+        errors.push(error(
+            "username",
+            "length",
+            format!(
+                "username must be {} to {} characters long",
+                config.username_min_len, config.username_max_len
+            ),
+        ));
+    }
+
+    let allowed = |c: char| c.is_ascii_alphanumeric() || config.username_extra_chars.contains(c);
+    if !username.chars().all(allowed) {
+        errors.push(error(
+            "username",
+            "charset",
+            format!(
+                "username may only contain letters, digits and {:?}",
+                config.username_extra_chars
+            ),
+        ));
+    } else if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        errors.push(error(
+            "username",
+            "charset",
+            String::from("username must start with a letter or digit"),
+        ));
+    }
+
+    if config.reserved_usernames.contains(&username.to_lowercase()) {
+        errors.push(error(
+            "username",
+            "reserved",
+            String::from("username is reserved"),
+        ));
+    }
+
+    errors
+}
+
+// Checks a password against the configured strength, username is the account it is for
+pub fn password(config: &Config, password: &str, username: &str) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    let len = password.chars().count();
+
+    if len < config.password_min_len {
+        errors.push(error(
+            "password",
+            "too_short",
+            format!(
+                "password must be at least {} characters long",
+                config.password_min_len
+            ),
+        ));
+    } else if len > MAX_PASSWORD_LEN {
+        errors.push(error(
+            "password",
+            "too_long",
+            format!(
+                "password must be at most {} characters long",
+                MAX_PASSWORD_LEN
+            ),
+        ));
+    }
+
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|&&class| class).count() < config.password_min_classes {
+        errors.push(error(
+            "password",
+            "too_weak",
+            format!(
+                "password must mix at least {} of lowercase, uppercase, digits and symbols",
+                config.password_min_classes
+            ),
+        ));
+    }
+
+    if !username.is_empty() && password.to_lowercase().contains(&username.to_lowercase()) {
+        errors.push(error(
+            "password",
+            "contains_username",
+            String::from("password must not contain the username"),
+        ));
+    }
+
+    errors
+}
+
+// Checks the shape of an email address, it is only proven by mailing it
+pub fn email(email: &str) -> Vec<FieldError> {
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !domain.contains('@')
+                && !email.contains(char::is_whitespace)
+                && email.len() <= MAX_EMAIL_LEN
+        }
+        None => false,
+    };
+
+    if valid {
+        Vec::new()
+    } else {
+        vec![error(
+            "email",
+            "invalid",
+            String::from("email is not a valid address"),
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        let mut config = Config::from_env();
+        config.username_min_len = 3;
+        config.username_max_len = 32;
+        config.username_extra_chars = String::from("_-.");
+        config.reserved_usernames = vec![String::from("admin")];
+        config.password_min_len = 8;
+        config.password_min_classes = 2;
+        config
+    }
+
+    fn codes(errors: Vec<FieldError>) -> Vec<&'static str> {
+        errors.into_iter().map(|e| e.code).collect()
+    }
+
+    #[test]
+    fn username_accepts_allowed_names() {
+        let config = config();
+        for name in ["bob", "alice_b", "a.b-c", "x1y2z3", &"a".repeat(32)] {
+            assert!(username(&config, name).is_empty(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn username_checks_length_in_characters() {
+        let config = config();
+        assert_eq!(codes(username(&config, "ab")), ["length"]);
+        assert_eq!(codes(username(&config, &"a".repeat(33))), ["length"]);
+        // Three characters, more bytes, fail on charset rather than length
+        assert_eq!(codes(username(&config, "äöü")), ["charset"]);
+    }
+
+    #[test]
+    fn username_checks_charset_and_first_character() {
+        let config = config();
+        assert_eq!(codes(username(&config, "bob smith")), ["charset"]);
+        assert_eq!(codes(username(&config, "bob@home")), ["charset"]);
+        assert_eq!(codes(username(&config, "_bob")), ["charset"]);
+        assert_eq!(codes(username(&config, ".bob")), ["charset"]);
+    }
+
+    #[test]
+    fn username_refuses_reserved_names_in_any_case() {
+        let config = config();
+        assert_eq!(codes(username(&config, "admin")), ["reserved"]);
+        assert_eq!(codes(username(&config, "AdMiN")), ["reserved"]);
+    }
+
+    #[test]
+    fn password_accepts_strong_passwords() {
+        let config = config();
+        assert!(password(&config, "Correct-Horse-9", "bob").is_empty());
+        assert!(password(&config, "lowercase1", "bob").is_empty());
+    }
+
+    #[test]
+    fn password_checks_length() {
+        let config = config();
+        assert_eq!(codes(password(&config, "Ab1", "bob")), ["too_short"]);
+        assert_eq!(
+            codes(password(&config, &"Ab1".repeat(400), "bob")),
+            ["too_long"]
+        );
+    }
+
+    #[test]
+    fn password_checks_character_classes() {
+        let config = config();
+        assert_eq!(codes(password(&config, "lowercase", "bob")), ["too_weak"]);
+        assert_eq!(codes(password(&config, "12345678", "bob")), ["too_weak"]);
+    }
+
+    #[test]
+    fn password_refuses_the_username_in_any_case() {
+        let config = config();
+        assert_eq!(
+            codes(password(&config, "my-Alice-123", "alice")),
+            ["contains_username"]
+        );
+    }
+
+    #[test]
+    fn password_lists_every_broken_rule() {
+        let config = config();
+        assert_eq!(
+            codes(password(&config, "bob", "bob")),
+            ["too_short", "too_weak", "contains_username"]
+        );
+    }
+
+    #[test]
+    fn email_accepts_plain_addresses() {
+        for address in ["a@example.com", "first.last+tag@mail.example.org"] {
+            assert!(email(address).is_empty(), "{}", address);
+        }
+    }
+
+    #[test]
+    fn email_refuses_malformed_addresses() {
+        let long = format!("{}@example.com", "a".repeat(250));
+        for address in [
+            "",
+            "example.com",
+            "@example.com",
+            "a@localhost",
+            "a@.example.com",
+            "a@example.com.",
+            "a@b@example.com",
+            "a b@example.com",
+            long.as_str(),
+        ] {
+            assert_eq!(codes(email(address)), ["invalid"], "{}", address);
+        }
+    }
+}
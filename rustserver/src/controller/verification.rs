@@ -0,0 +1,169 @@
+use actix_web::{
+    error::InternalError,
+    http::StatusCode,
+    web::{Data, Json},
+};
+use chrono::Utc;
+use sea_orm::{
+    prelude::Uuid, sea_query::Expr, ActiveEnum, ActiveModelTrait, ColumnTrait, DatabaseConnection,
+    DbErr, EntityTrait, QueryFilter, Set,
+};
+
+use crate::{
+    config::Config,
+    crypto::token_digest,
+    mail::{Mailer, Message},
+    model::{
+        action_token,
+        user::{self, LoginResponse, Status},
+    },
+};
+
+use super::to_internal_error;
+
+// Mails the user a single-use link confirming their email address
+// Does nothing for users without an email address
+// Mail failures are logged, not returned, so callers answer the same either way
+pub async fn send_verification(
+    db: &DatabaseConnection,
+    config: &Config,
+    mailer: &dyn Mailer,
+    user: &user::Model,
+) -> Result<(), InternalError<DbErr>> {
+    let email = match &user.email {
+        Some(email) => email.clone(),
+        None => return Ok(()),
+    };
+
+    // Only the latest link works
+    action_token::Entity::delete_many()
+        .filter(action_token::Column::UserId.eq(user.id))
+        .filter(action_token::Column::Purpose.eq(action_token::EMAIL_VERIFICATION))
+        .exec(db)
+        .await
+        .map_err(to_internal_error)?;
+
+    let uuid = Uuid::new_v4();
+    let now = Utc::now();
+
+    action_token::ActiveModel {
+        hash: Set(token_digest(&config.token_secret, uuid.as_bytes())),
+        user_id: Set(user.id),
+        purpose: Set(action_token::EMAIL_VERIFICATION.to_owned()),
+        expires_at: Set((now + config.verification_token_lifetime).naive_utc()),
+        attempts: Set(0),
+        created_at: Set(now.naive_utc()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await
+    .map_err(to_internal_error)?;
+
+    let message = Message {
+        to: email,
+        subject: String::from("Verify your aeroFans email address"),
+        body: format!(
+            "Hi {},\r\n\r\nUse this link to verify your email address, it expires in {} hours:\r\n{}/verify?token={}\r\n\r\nIf you didn't sign up, ignore this message.",
+            user.username,
+            config.verification_token_lifetime.num_hours(),
+            config.frontend_url,
+            uuid
+        ),
+    };
+
+    if let Err(e) = mailer.send(&message) {
+        eprintln!("failed to mail a verification link: {}", e);
+    }
+
+    Ok(())
+}
+
+// POST /verify-email/request
+// Takes in JSON encoded user VerificationRequest
+// If the account is pending verification, mails a new verification link
+// Always returns 200 OK with the same JSON encoded LoginResponse, so it can't reveal users
+pub async fn request(
+    Json(request): Json<user::VerificationRequest>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+    mailer: Data<dyn Mailer>,
+) -> Result<Json<LoginResponse>, InternalError<DbErr>> {
+    let user = user::Entity::find()
+        .filter(user::Column::Username.eq(request.username))
+        .filter(user::Column::Status.eq(Status::PendingVerification.to_value()))
+        .one(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    if let Some(user) = user {
+        send_verification(db.as_ref(), &config, mailer.as_ref(), &user).await?;
+    }
+
+    Ok(Json(LoginResponse {
+        status: true,
+        message: "if the account is pending verification, a verification link was sent",
+    }))
+}
+
+// POST /verify-email/confirm
+// Takes in JSON encoded user VerificationConfirm
+// On success, consumes the verification token, marks the email verified, activates the account
+// and returns 200 OK
+// with JSON encoded LoginResponse
+// If the verification token is invalid or expired, returns 400 Bad Request
+pub async fn confirm(
+    Json(confirm): Json<user::VerificationConfirm>,
+    db: Data<DatabaseConnection>,
+    config: Data<Config>,
+) -> Result<Json<LoginResponse>, InternalError<DbErr>> {
+    let invalid = || {
+        InternalError::new(
+            DbErr::Custom("invalid verification token".to_string()),
+            StatusCode::BAD_REQUEST,
+        )
+    };
+
+    let uuid = Uuid::parse_str(&confirm.token).map_err(|_| invalid())?;
+
+    let verification = action_token::Entity::find()
+        .filter(action_token::Column::Hash.eq(token_digest(&config.token_secret, uuid.as_bytes())))
+        .filter(action_token::Column::Purpose.eq(action_token::EMAIL_VERIFICATION))
+        .filter(action_token::Column::ExpiresAt.gt(Utc::now().naive_utc()))
+        .one(db.as_ref())
+        .await
+        .map_err(to_internal_error)?
+        .ok_or_else(invalid)?;
+
+    let consumed = action_token::Entity::delete_many()
+        .filter(action_token::Column::Id.eq(verification.id))
+        .exec(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+    if consumed.rows_affected == 0 {
+        return Err(invalid());
+    }
+
+    user::Entity::update_many()
+        .col_expr(
+            user::Column::EmailVerifiedAt,
+            Expr::value(Utc::now().naive_utc()),
+        )
+        .filter(user::Column::Id.eq(verification.user_id))
+        .exec(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    // Suspended or banned accounts stay that way
+    user::Entity::update_many()
+        .col_expr(user::Column::Status, Expr::value(Status::Active.to_value()))
+        .filter(user::Column::Id.eq(verification.user_id))
+        .filter(user::Column::Status.eq(Status::PendingVerification.to_value()))
+        .exec(db.as_ref())
+        .await
+        .map_err(to_internal_error)?;
+
+    Ok(Json(LoginResponse {
+        status: true,
+        message: "email verified",
+    }))
+}
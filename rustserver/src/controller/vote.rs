@@ -0,0 +1,250 @@
+use actix_web::{
+    error::InternalError,
+    web::{Data, Json, Path},
+};
+use chrono::Utc;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection,
+    DatabaseTransaction, DbErr, EntityTrait, IntoActiveModel, QueryFilter, QuerySelect, Set,
+};
+
+use crate::model::{
+    post, reply,
+    token::{self, Scope},
+    vote,
+};
+
+use super::{permission::check_scope, to_bad_request, to_internal_error, to_not_found};
+
+// Sets the user's vote on a post, or on one of its replies, None retracting it
+// The item is locked first, so concurrent votes on it adjust its score one after another
+// Votes on a reply that was deleted since can still be retracted
+async fn set_vote(
+    db: &DatabaseConnection,
+    user_id: i64,
+    post_id: i64,
+    reply_id: Option<i64>,
+    value: Option<i32>,
+) -> Result<vote::Output, InternalError<DbErr>> {
+    let txn = db.begin().await.map_err(to_internal_error)?;
+
+    let found = match reply_id {
+        Some(reply_id) => {
+            let mut query =
+                reply::Entity::find_by_id(reply_id).filter(reply::Column::PostId.eq(post_id));
+            if value.is_some() {
+                query = query.filter(reply::Column::DeletedAt.is_null());
+            }
+            query.lock_exclusive().one(&txn).await.map(|r| r.is_some())
+        }
+        None => post::Entity::find_by_id(post_id)
+            .lock_exclusive()
+            .one(&txn)
+            .await
+            .map(|p| p.is_some()),
+    };
+    if !found.map_err(to_internal_error)? {
+        return Err(to_not_found(DbErr::RecordNotFound(String::new())));
+    }
+
+    let existing = vote::Entity::find()
+        .filter(vote::Column::UserId.eq(user_id))
+        .filter(vote::Column::PostId.eq(post_id))
+        .filter(match reply_id {
+            Some(reply_id) => vote::Column::ReplyId.eq(reply_id),
+            None => vote::Column::ReplyId.is_null(),
+        })
+        .one(&txn)
+        .await
+        .map_err(to_internal_error)?;
+
+    let delta = value.unwrap_or(0) - existing.as_ref().map_or(0, |vote| vote.value);
+
+    match (existing, value) {
+        (Some(existing), Some(value)) => {
+            vote::ActiveModel {
+                id: Set(existing.id),
+                value: Set(value),
+                ..Default::default()
+            }
+            .update(&txn)
+            .await
+            .map_err(to_internal_error)?;
+        }
+        (Some(existing), None) => {
+            existing
+                .into_active_model()
+                .delete(&txn)
+                .await
+                .map_err(to_internal_error)?;
+        }
+        (None, Some(value)) => {
+            vote::ActiveModel {
+                user_id: Set(user_id),
+                post_id: Set(post_id),
+                reply_id: Set(reply_id),
+                value: Set(value),
+                created_at: Set(Utc::now().naive_utc()),
+                ..Default::default()
+            }
+            .insert(&txn)
+            .await
+            .map_err(to_internal_error)?;
+        }
+        (None, None) => {}
+    }
+
+    let score = add_score(&txn, post_id, reply_id, delta)
+        .await
+        .map_err(to_internal_error)?;
+
+    txn.commit().await.map_err(to_internal_error)?;
+
+    Ok(vote::Output {
+        score,
+        my_vote: value,
+    })
+}
+
+// Adds delta to the score of the post, or of the reply, returning the new score
+async fn add_score(
+    txn: &DatabaseTransaction,
+    post_id: i64,
+    reply_id: Option<i64>,
+    delta: i32,
+) -> Result<i32, DbErr> {
+    match reply_id {
+        Some(reply_id) => {
+            reply::Entity::update_many()
+                .col_expr(
+                    reply::Column::Score,
+                    Expr::col(reply::Column::Score).add(delta),
+                )
+                .filter(reply::Column::Id.eq(reply_id))
+                .exec(txn)
+                .await?;
+
+            reply::Entity::find_by_id(reply_id)
+                .one(txn)
+                .await?
+                .map(|reply| reply.score)
+                .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+        }
+        None => {
+            post::Entity::update_many()
+                .col_expr(
+                    post::Column::Score,
+                    Expr::col(post::Column::Score).add(delta),
+                )
+                .filter(post::Column::Id.eq(post_id))
+                .exec(txn)
+                .await?;
+
+            post::Entity::find_by_id(post_id)
+                .one(txn)
+                .await?
+                .map(|post| post.score)
+                .ok_or_else(|| DbErr::RecordNotFound(String::new()))
+        }
+    }
+}
+
+fn value(input: &vote::Input) -> Result<i32, InternalError<DbErr>> {
+    match input.value {
+        1 | -1 => Ok(input.value),
+        _ => Err(to_bad_request(DbErr::Custom(
+            "value must be 1 or -1".to_string(),
+        ))),
+    }
+}
+
+// PUT /post/{post_id}/vote
+// Takes in JSON encoded vote Input and user auth
+// Replaces the user's earlier vote on the post, if any
+// On success, returns 200 OK with JSON encoded vote Output
+// If value is not 1 or -1, returns 400 Bad Request
+// If post_id does not exist, returns 404 Not Found
+pub async fn cast_post(
+    Json(input): Json<vote::Input>,
+    param: Path<i64>,
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<Json<vote::Output>, InternalError<DbErr>> {
+    check_scope(&token, Scope::PostsWrite)?;
+
+    let value = value(&input)?;
+
+    set_vote(
+        db.as_ref(),
+        token.user_id,
+        param.into_inner(),
+        None,
+        Some(value),
+    )
+    .await
+    .map(Json)
+}
+
+// DELETE /post/{post_id}/vote
+// Takes in user auth
+// On success, removes the user's vote on the post and returns 200 OK with JSON encoded
+// vote Output
+// If post_id does not exist, returns 404 Not Found
+pub async fn retract_post(
+    param: Path<i64>,
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<Json<vote::Output>, InternalError<DbErr>> {
+    check_scope(&token, Scope::PostsWrite)?;
+
+    set_vote(db.as_ref(), token.user_id, param.into_inner(), None, None)
+        .await
+        .map(Json)
+}
+
+// PUT /post/{post_id}/reply/{reply_id}/vote
+// Takes in JSON encoded vote Input and user auth
+// Replaces the user's earlier vote on the reply, if any
+// On success, returns 200 OK with JSON encoded vote Output
+// If value is not 1 or -1, returns 400 Bad Request
+// If post_id, reply_id does not exist or the reply was deleted, returns 404 Not Found
+pub async fn cast_reply(
+    Json(input): Json<vote::Input>,
+    param: Path<(i64, i64)>,
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<Json<vote::Output>, InternalError<DbErr>> {
+    check_scope(&token, Scope::RepliesWrite)?;
+
+    let value = value(&input)?;
+    let (post_id, reply_id) = param.into_inner();
+
+    set_vote(
+        db.as_ref(),
+        token.user_id,
+        post_id,
+        Some(reply_id),
+        Some(value),
+    )
+    .await
+    .map(Json)
+}
+
+// DELETE /post/{post_id}/reply/{reply_id}/vote
+// Takes in user auth
+// On success, removes the user's vote on the reply and returns 200 OK with JSON encoded
+// vote Output, also once the reply was deleted
+// If post_id, reply_id does not exist, returns 404 Not Found
+pub async fn retract_reply(
+    param: Path<(i64, i64)>,
+    db: Data<DatabaseConnection>,
+    token: token::Model,
+) -> Result<Json<vote::Output>, InternalError<DbErr>> {
+    check_scope(&token, Scope::RepliesWrite)?;
+
+    let (post_id, reply_id) = param.into_inner();
+
+    set_vote(db.as_ref(), token.user_id, post_id, Some(reply_id), None)
+        .await
+        .map(Json)
+}
@@ -0,0 +1,220 @@
+use std::convert::TryFrom;
+
+use argon2::{
+    password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use bcrypt::hash_with_salt;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+// Stored as the password of accounts that only sign in through SSO, it matches no password
+pub const NO_PASSWORD: &str = "!";
+
+// Computes the keyed digest a token is stored under
+// Hex encoded HMAC-SHA256 of the token with the server secret
+pub fn token_digest(secret: &[u8], token: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(token);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Computes the digest a long-lived random code is stored under
+// Hex encoded SHA-256, unkeyed so stored codes survive a new or rotated server secret
+pub fn code_digest(code: &[u8]) -> String {
+    hex::encode(Sha256::digest(code))
+}
+
+// Computes the digest tokens were stored under before keyed digests
+// Only used to find and upgrade rows written by older versions
+pub fn legacy_token_digest(token: &[u8]) -> String {
+    hash_with_salt(token, 4, &[0; 16][..]).unwrap().to_string()
+}
+
+// Hashes a password with Argon2id, returning the PHC string to store
+pub fn hash_password(password: &str, params: &Params) -> Result<String, password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+}
+
+// Checks a password against a stored Argon2 PHC string or bcrypt hash
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    if is_bcrypt(hash) {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Whether a stored hash should be replaced with one using the current algorithm and params
+pub fn needs_rehash(hash: &str, params: &Params) -> bool {
+    if is_bcrypt(hash) {
+        return true;
+    }
+
+    match PasswordHash::new(hash) {
+        Ok(parsed) => {
+            parsed.algorithm != Algorithm::Argon2id.ident()
+                || Params::try_from(&parsed).map_or(true, |p| {
+                    p.m_cost() != params.m_cost()
+                        || p.t_cost() != params.t_cost()
+                        || p.p_cost() != params.p_cost()
+                })
+        }
+        Err(_) => true,
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    hash.starts_with("$2")
+}
+
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+
+// Computes the RFC 6238 TOTP code for a time step
+fn totp(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    code % 10u32.pow(TOTP_DIGITS)
+}
+
+// Checks a TOTP code against the current time step and its neighbours, for clock drift
+// Steps up to last_step are refused so a code can't be replayed
+// Returns the matched time step
+pub fn verify_totp(secret: &[u8], code: &str, last_step: Option<i64>) -> Option<i64> {
+    verify_totp_at(secret, code, last_step, chrono::Utc::now().timestamp())
+}
+
+fn verify_totp_at(secret: &[u8], code: &str, last_step: Option<i64>, now: i64) -> Option<i64> {
+    let code: u32 = code.trim().parse().ok()?;
+    let current = now / TOTP_STEP_SECONDS;
+
+    (current - 1..=current + 1)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| totp(secret, *step) == code)
+}
+
+// Generates a fresh TOTP secret, base32 encoded for authenticator apps
+pub fn generate_totp_secret() -> String {
+    let mut secret = [0; 20];
+    OsRng.fill_bytes(&mut secret);
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, &secret)
+}
+
+pub fn decode_totp_secret(secret: &str) -> Option<Vec<u8>> {
+    base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)
+}
+
+// Builds the otpauth URI authenticator apps enrol from
+pub fn totp_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = percent_encode(issuer),
+        account = percent_encode(account),
+        secret = secret,
+        digits = TOTP_DIGITS,
+        period = TOTP_STEP_SECONDS
+    )
+}
+
+// Generates a one-time recovery code of 80 random bits, like 3f9a-c2e1-77b0-09d4-e5a1
+pub fn generate_recovery_code() -> String {
+    let mut bytes = [0; 10];
+    OsRng.fill_bytes(&mut bytes);
+    let hex = hex::encode(bytes);
+    (0..hex.len())
+        .step_by(4)
+        .map(|i| &hex[i..i + 4])
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// Generates a random string of URL-safe characters from the given number of random bytes
+pub fn generate_urlsafe(bytes: usize) -> String {
+    let mut buf = vec![0; bytes];
+    OsRng.fill_bytes(&mut buf);
+    base64::encode_config(buf, base64::URL_SAFE_NO_PAD)
+}
+
+// Derives the S256 PKCE code challenge of a code verifier
+pub fn pkce_challenge(verifier: &str) -> String {
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, SHA-1, keeping the last 6 of the 8 digits
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+    const RFC_VECTORS: [(i64, &str); 6] = [
+        (59, "287082"),
+        (1111111109, "081804"),
+        (1111111111, "050471"),
+        (1234567890, "005924"),
+        (2000000000, "279037"),
+        (20000000000, "353130"),
+    ];
+
+    #[test]
+    fn verify_totp_accepts_rfc_6238_vectors() {
+        for (time, code) in RFC_VECTORS {
+            let step = time / TOTP_STEP_SECONDS;
+            assert_eq!(verify_totp_at(RFC_SECRET, code, None, time), Some(step));
+        }
+    }
+
+    #[test]
+    fn verify_totp_allows_one_step_of_drift() {
+        let (time, code) = RFC_VECTORS[1];
+        let step = time / TOTP_STEP_SECONDS;
+        assert_eq!(
+            verify_totp_at(RFC_SECRET, code, None, time + TOTP_STEP_SECONDS),
+            Some(step)
+        );
+        assert_eq!(
+            verify_totp_at(RFC_SECRET, code, None, time + 2 * TOTP_STEP_SECONDS),
+            None
+        );
+    }
+
+    #[test]
+    fn verify_totp_refuses_replayed_steps() {
+        let (time, code) = RFC_VECTORS[3];
+        let step = time / TOTP_STEP_SECONDS;
+        assert_eq!(verify_totp_at(RFC_SECRET, code, Some(step), time), None);
+        assert_eq!(verify_totp_at(RFC_SECRET, "12345x", None, time), None);
+    }
+}
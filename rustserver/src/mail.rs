@@ -0,0 +1,67 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use chrono::Utc;
+use sea_orm::prelude::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Delivers mail to users, implementations decide where it goes
+pub trait Mailer: Send + Sync {
+    fn send(&self, message: &Message) -> io::Result<()>;
+}
+
+// Writes each message as a file in a local outbox directory
+pub struct OutboxMailer {
+    dir: PathBuf,
+}
+
+impl OutboxMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        OutboxMailer { dir: dir.into() }
+    }
+}
+
+impl Mailer for OutboxMailer {
+    fn send(&self, message: &Message) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let now = Utc::now();
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            now.format("%Y%m%dT%H%M%S"),
+            Uuid::new_v4()
+        ));
+
+        let mut file = fs::File::create(path)?;
+        write!(
+            file,
+            "Date: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+            now.to_rfc2822(),
+            message.to,
+            message.subject,
+            message.body
+        )
+    }
+}
+
+// Prints each message to standard output, for development without an outbox
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, message: &Message) -> io::Result<()> {
+        println!(
+            "mail to {}: {}\n{}",
+            message.to, message.subject, message.body
+        );
+        Ok(())
+    }
+}
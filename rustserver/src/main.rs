@@ -1,11 +1,22 @@
+mod config;
 mod controller;
+mod crypto;
+mod mail;
 mod model;
+mod oidc;
 
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use actix_cors::Cors;
-use actix_web::{web::Data, App, HttpServer};
-use model::init;
+use actix_web::{
+    http::header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER},
+    middleware::from_fn,
+    web::Data,
+    App, HttpServer,
+};
+use config::Config;
+use mail::{LogMailer, Mailer, OutboxMailer};
+use model::{bootstrap_admin, bootstrap_board, init, purge_expired};
 use sea_orm::{ConnectOptions, Database};
 
 #[actix_web::main]
@@ -25,14 +36,50 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let pool = Data::new(Database::connect(opt).await?);
     init(pool.as_ref()).await;
 
+    let config = Data::new(Config::from_env());
+    bootstrap_admin(pool.as_ref(), config.as_ref()).await?;
+    bootstrap_board(pool.as_ref(), config.as_ref()).await?;
+
+    let mailer: Arc<dyn Mailer> = match config.mail_sink.as_str() {
+        "log" => Arc::new(LogMailer),
+        _ => Arc::new(OutboxMailer::new(&config.mail_outbox_dir)),
+    };
+    let mailer: Data<dyn Mailer> = Data::from(mailer);
+
+    // Periodically purge expired tokens
+    {
+        let pool = pool.clone();
+        let config = config.clone();
+
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(config.cleanup_interval);
+            loop {
+                interval.tick().await;
+                let _ = purge_expired(pool.as_ref(), config.as_ref()).await;
+            }
+        });
+    }
+
     // Start server
     HttpServer::new(move || {
-        // Add PERMISSIVE CORS controls
-        let cors = Cors::permissive();
+        // Only the configured origins may make credentialed requests
+        let cors = config
+            .allowed_origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+            .allowed_methods(["GET", "POST", "PUT", "PATCH", "DELETE"])
+            .allowed_headers([CONTENT_TYPE, AUTHORIZATION, controller::CSRF_HEADER])
+            .expose_headers([RETRY_AFTER])
+            .supports_credentials()
+            .max_age(3600);
 
         App::new()
+            .wrap(from_fn(controller::csrf_protect))
+            .wrap(from_fn(controller::renew_token_cookie))
             .wrap(cors)
             .app_data(pool.clone())
+            .app_data(config.clone())
+            .app_data(mailer.clone())
             .configure(controller::config)
     })
     .bind(("0.0.0.0", 8000))?
@@ -0,0 +1,52 @@
+use super::*;
+
+// Authentication events worth keeping for investigations
+pub const REGISTER: &str = "register";
+pub const LOGIN_SUCCESS: &str = "login_success";
+pub const LOGIN_FAILURE: &str = "login_failure";
+pub const LOGIN_LOCKED: &str = "login_locked";
+pub const LOGIN_CHALLENGE: &str = "login_challenge";
+pub const TOKEN_REJECTED: &str = "token_rejected";
+pub const LOGOUT: &str = "logout";
+pub const LOGOUT_ALL: &str = "logout_all";
+
+// Entries younger than this can't be deleted, see the audit_log trigger in init
+pub const MIN_RETENTION_DAYS: i64 = 90;
+
+// Filters for reading the audit log, all optional
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditQuery {
+    pub event: Option<String>,
+    // Matches the username given, ignoring case, even when no such user exists
+    pub username: Option<String>,
+    pub user_id: Option<i64>,
+    pub ip: Option<String>,
+    pub since: Option<DateTime>,
+    pub until: Option<DateTime>,
+    // Only entries older than this id, to page back through the log
+    pub before: Option<i64>,
+    pub limit: Option<u64>,
+}
+
+// Record of an authentication event
+// Rows are never updated, only deleted once past the retention, and kept without foreign keys
+// so they outlive users
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "audit_log")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    pub event: String,
+    pub user_id: Option<i64>,
+    // Username as given, also for failed logins to unknown accounts
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub detail: Option<String>,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,71 @@
+use super::*;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Input {
+    pub slug: String,
+    pub name: String,
+    pub description: Option<String>,
+    // Boards are listed by position, then name
+    pub position: Option<i32>,
+    // Slug of the parent board
+    pub parent: Option<String>,
+}
+
+// Omitted fields are left alone, an empty description or parent clears it
+#[derive(Debug, Clone, Deserialize)]
+pub struct Update {
+    pub slug: Option<String>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub position: Option<i32>,
+    pub parent: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, FromQueryResult)]
+pub struct Output {
+    pub id: i64,
+    pub slug: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub position: i32,
+    pub parent_id: Option<i64>,
+    pub post_count: i64,
+    pub created_at: DateTime,
+}
+
+// A sub-forum, every post belongs to exactly one
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "boards")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    #[sea_orm(unique)]
+    pub slug: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub position: i32,
+    pub parent_id: Option<i64>,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "Entity",
+        from = "Column::ParentId",
+        to = "Column::Id",
+        on_update = "Cascade",
+        on_delete = "Restrict"
+    )]
+    Parent,
+    #[sea_orm(has_many = "super::post::Entity")]
+    Post,
+}
+
+impl Related<super::post::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Post.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
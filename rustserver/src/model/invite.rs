@@ -0,0 +1,69 @@
+use super::*;
+
+// Prefix marking invite codes
+pub const INVITE_PREFIX: &str = "afi_";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InviteInput {
+    pub max_uses: Option<i32>,
+    pub expires_in_days: Option<i64>,
+}
+
+// Returned once on creation, the only time the code is visible
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedInvite {
+    #[serde(flatten)]
+    pub invite: Model,
+    pub code: String,
+}
+
+// Formats an invite code
+pub fn format_invite(uuid: Uuid) -> String {
+    format!("{}{}", INVITE_PREFIX, uuid.to_simple())
+}
+
+// Parses an invite code
+pub fn parse_invite(code: &str) -> Option<Uuid> {
+    code.trim()
+        .strip_prefix(INVITE_PREFIX)
+        .and_then(|c| Uuid::parse_str(c).ok())
+}
+
+// Codes letting people register while registration is invite-only
+// Kept after running out, so accounts can be traced back to who invited them
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "invites")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    #[serde(skip_serializing)]
+    #[sea_orm(unique, column_type = "String(Some(64))")]
+    pub hash: String,
+    pub created_by: i64,
+    // None for unlimited uses
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    // None for no expiry
+    pub expires_at: Option<DateTime>,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::user::Entity",
+        from = "Column::CreatedBy",
+        to = "super::user::Column::Id",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    User,
+}
+
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
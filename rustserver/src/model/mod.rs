@@ -1,14 +1,142 @@
 use sea_orm::{
     entity::prelude::*,
-    sea_query::{Index, IndexType, PostgresQueryBuilder},
-    ConnectionTrait, FromQueryResult, Schema, Statement,
+    sea_query::{Expr, Index, IndexType, PostgresQueryBuilder},
+    Condition, ConnectionTrait, FromQueryResult, Schema, Set, Statement,
 };
 use serde::{Deserialize, Serialize};
 
+use crate::config::Config;
+
+pub mod action_token;
+pub mod audit;
+pub mod board;
+pub mod invite;
+pub mod login_attempt;
+pub mod moderation;
+pub mod page;
 pub mod post;
+pub mod post_tag;
+pub mod recovery_code;
 pub mod reply;
+pub mod search;
+pub mod tag;
 pub mod token;
 pub mod user;
+pub mod vote;
+
+// Deletes rows that have outlived their purpose
+pub async fn purge_expired(db: &DatabaseConnection, config: &Config) -> Result<(), DbErr> {
+    let now = chrono::Utc::now().naive_utc();
+
+    let mut expired = Condition::any().add(token::Column::ExpiresAt.lte(now));
+    if let Some(idle_timeout) = config.token_idle_timeout {
+        expired = expired.add(
+            Condition::all()
+                .add(token::Column::Scopes.is_null())
+                .add(token::Column::LastUsedAt.lte(now - idle_timeout)),
+        );
+    }
+
+    token::Entity::delete_many()
+        .filter(expired)
+        .exec(db)
+        .await?;
+
+    action_token::Entity::delete_many()
+        .filter(action_token::Column::ExpiresAt.lte(now))
+        .exec(db)
+        .await?;
+
+    login_attempt::Entity::delete_many()
+        .filter(login_attempt::Column::LastFailureAt.lte(now - config.login_failure_window))
+        .filter(
+            Condition::any()
+                .add(login_attempt::Column::LockedUntil.is_null())
+                .add(login_attempt::Column::LockedUntil.lte(now)),
+        )
+        .exec(db)
+        .await?;
+
+    if let Some(retention) = config.audit_retention {
+        audit::Entity::delete_many()
+            .filter(audit::Column::CreatedAt.lt(now - retention))
+            .exec(db)
+            .await?;
+    }
+
+    // Tags no post uses any more
+    tag::Entity::delete_many()
+        .filter(Expr::cust(
+            "NOT EXISTS (SELECT 1 FROM post_tags WHERE post_tags.tag_id = tags.id)",
+        ))
+        .exec(db)
+        .await?;
+
+    Ok(())
+}
+
+// Creates the board posts go to when none is given, so there is always one to post in
+// Posts from before boards are moved to it, after which every post needs a board
+pub async fn bootstrap_board(db: &DatabaseConnection, config: &Config) -> Result<(), DbErr> {
+    let found = board::Entity::find()
+        .filter(board::Column::Slug.eq(config.default_board.as_str()))
+        .one(db)
+        .await?;
+
+    let board = match found {
+        Some(board) => board,
+        None => {
+            board::ActiveModel {
+                slug: Set(config.default_board.clone()),
+                name: Set(config.default_board.clone()),
+                position: Set(0),
+                created_at: Set(chrono::Utc::now().naive_utc()),
+                ..Default::default()
+            }
+            .insert(db)
+            .await?
+        }
+    };
+
+    post::Entity::update_many()
+        .col_expr(post::Column::BoardId, Expr::value(board.id))
+        .filter(post::Column::BoardId.is_null())
+        .exec(db)
+        .await?;
+
+    db.execute(Statement::from_string(
+        db.get_database_backend(),
+        String::from(
+            "DO $$ BEGIN
+                IF EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'posts' AND column_name = 'board_id' AND is_nullable = 'YES'
+                ) THEN
+                    ALTER TABLE posts ALTER COLUMN board_id SET NOT NULL;
+                END IF;
+            END $$",
+        ),
+    ))
+    .await?;
+
+    Ok(())
+}
+
+// Gives the configured user the admin role, so roles can be managed at all
+pub async fn bootstrap_admin(db: &DatabaseConnection, config: &Config) -> Result<(), DbErr> {
+    if let Some(username) = &config.admin_username {
+        user::Entity::update_many()
+            .col_expr(
+                user::Column::Role,
+                Expr::value(user::Role::Admin.to_value()),
+            )
+            .filter(user::Column::Username.eq(username.as_str()))
+            .exec(db)
+            .await?;
+    }
+
+    Ok(())
+}
 
 pub async fn init(db: &DatabaseConnection) {
     let builder = db.get_database_backend();
@@ -20,12 +148,200 @@ pub async fn init(db: &DatabaseConnection) {
     let _ = db
         .execute(builder.build(&schema.create_table_from_entity(token::Entity)))
         .await;
+    let _ = db
+        .execute(builder.build(&schema.create_table_from_entity(board::Entity)))
+        .await;
     let _ = db
         .execute(builder.build(&schema.create_table_from_entity(post::Entity)))
         .await;
     let _ = db
         .execute(builder.build(&schema.create_table_from_entity(reply::Entity)))
         .await;
+    let _ = db
+        .execute(builder.build(&schema.create_table_from_entity(tag::Entity)))
+        .await;
+    let _ = db
+        .execute(builder.build(&schema.create_table_from_entity(post_tag::Entity)))
+        .await;
+    let _ = db
+        .execute(builder.build(&schema.create_table_from_entity(vote::Entity)))
+        .await;
+    let _ = db
+        .execute(builder.build(&schema.create_table_from_entity(action_token::Entity)))
+        .await;
+    let _ = db
+        .execute(builder.build(&schema.create_table_from_entity(moderation::Entity)))
+        .await;
+    let _ = db
+        .execute(builder.build(&schema.create_table_from_entity(login_attempt::Entity)))
+        .await;
+    let _ = db
+        .execute(builder.build(&schema.create_table_from_entity(recovery_code::Entity)))
+        .await;
+    let _ = db
+        .execute(builder.build(&schema.create_table_from_entity(invite::Entity)))
+        .await;
+    let _ = db
+        .execute(builder.build(&schema.create_table_from_entity(audit::Entity)))
+        .await;
+
+    // audit_log: append-only, even for the server itself, except for deleting rows older
+    // than audit::MIN_RETENTION_DAYS
+    let audit_log_append_only = format!(
+        "CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$ BEGIN
+            IF TG_OP = 'DELETE' AND TG_LEVEL = 'ROW' THEN
+                IF OLD.created_at < (now() AT TIME ZONE 'UTC') - interval '{} days' THEN
+                    RETURN OLD;
+                END IF;
+            END IF;
+            RAISE EXCEPTION 'audit_log is append-only';
+        END $$ LANGUAGE plpgsql",
+        audit::MIN_RETENTION_DAYS
+    );
+
+    // Bring tables created by older versions up to date
+    let migrations = [
+        // tokens: surrogate id as primary key, session metadata
+        "ALTER TABLE tokens ADD COLUMN IF NOT EXISTS id BIGSERIAL",
+        "ALTER TABLE tokens ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT now()",
+        "ALTER TABLE tokens ADD COLUMN IF NOT EXISTS last_used_at TIMESTAMP NOT NULL DEFAULT now()",
+        "ALTER TABLE tokens ADD COLUMN IF NOT EXISTS user_agent VARCHAR",
+        "ALTER TABLE tokens ADD COLUMN IF NOT EXISTS ip VARCHAR",
+        "DO $$ BEGIN
+            IF NOT EXISTS (
+                SELECT 1 FROM information_schema.key_column_usage
+                WHERE table_name = 'tokens' AND constraint_name = 'tokens_pkey' AND column_name = 'id'
+            ) THEN
+                ALTER TABLE tokens DROP CONSTRAINT tokens_pkey;
+                ALTER TABLE tokens ADD PRIMARY KEY (id);
+            END IF;
+        END $$",
+        // tokens: keyed digests are longer than the old bcrypt hashes
+        "ALTER TABLE tokens ALTER COLUMN hash TYPE VARCHAR(64)",
+        // tokens: personal access tokens
+        "ALTER TABLE tokens ADD COLUMN IF NOT EXISTS name VARCHAR",
+        "ALTER TABLE tokens ADD COLUMN IF NOT EXISTS scopes VARCHAR",
+        // action_tokens: codes tried per login challenge
+        "ALTER TABLE action_tokens ADD COLUMN IF NOT EXISTS attempts INTEGER NOT NULL DEFAULT 0",
+        // users: room for Argon2 PHC strings
+        "ALTER TABLE users ALTER COLUMN password TYPE VARCHAR",
+        // users: address for account mail
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS email VARCHAR",
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified_at TIMESTAMP",
+        // users: public profile
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS display_name VARCHAR",
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS bio VARCHAR",
+        // users: permissions
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS role VARCHAR NOT NULL DEFAULT 'user'",
+        // users: two-factor authentication
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_secret VARCHAR",
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_enabled BOOLEAN NOT NULL DEFAULT false",
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_last_step BIGINT",
+        // users: single sign-on
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS oidc_subject VARCHAR",
+        // users: account lifecycle
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS status VARCHAR NOT NULL DEFAULT 'active'",
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS suspended_until TIMESTAMP",
+        // users: invite tracing
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS invited_by BIGINT",
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS invite_id BIGINT",
+        // users: usernames are unique regardless of case
+        "CREATE UNIQUE INDEX IF NOT EXISTS \"idx-user-username-lower\" ON users (LOWER(username))",
+        // posts: titles for posts from before they had one, taken from the first line
+        "DO $$ BEGIN
+            IF NOT EXISTS (
+                SELECT 1 FROM information_schema.columns
+                WHERE table_name = 'posts' AND column_name = 'title'
+            ) THEN
+                ALTER TABLE posts ADD COLUMN title VARCHAR;
+                UPDATE posts SET title = COALESCE(
+                    NULLIF(LEFT(TRIM(SPLIT_PART(text, E'\\n', 1)), 200), ''), 'Untitled'
+                );
+                ALTER TABLE posts ALTER COLUMN title SET NOT NULL;
+            END IF;
+        END $$",
+        "ALTER TABLE posts ADD COLUMN IF NOT EXISTS summary VARCHAR",
+        // posts: boards, older posts are moved to the default board by bootstrap_board
+        "ALTER TABLE posts ADD COLUMN IF NOT EXISTS board_id BIGINT
+            CONSTRAINT \"fk-posts-board_id\" REFERENCES boards (id)
+            ON UPDATE CASCADE ON DELETE RESTRICT",
+        "ALTER TABLE posts ADD COLUMN IF NOT EXISTS updated_at TIMESTAMP",
+        // replies: threads, older replies are all top-level
+        "ALTER TABLE replies ADD COLUMN IF NOT EXISTS parent_reply_id BIGINT
+            CONSTRAINT \"fk-replies-parent_reply_id\" REFERENCES replies (id)
+            ON UPDATE CASCADE ON DELETE RESTRICT",
+        // replies: deleting a reply never takes the replies below it along
+        // the key is named fk-replies-replies when the table was created from the entity
+        "DO $$ DECLARE fk name; BEGIN
+            FOR fk IN
+                SELECT conname FROM pg_constraint
+                WHERE conrelid = 'replies'::regclass AND confrelid = 'replies'::regclass
+                    AND contype = 'f' AND confdeltype = 'c'
+            LOOP
+                EXECUTE format('ALTER TABLE replies DROP CONSTRAINT %I', fk);
+                EXECUTE format('ALTER TABLE replies ADD CONSTRAINT %I FOREIGN KEY (parent_reply_id)
+                    REFERENCES replies (id) ON UPDATE CASCADE ON DELETE RESTRICT', fk);
+            END LOOP;
+        END $$",
+        "ALTER TABLE replies ADD COLUMN IF NOT EXISTS depth INTEGER NOT NULL DEFAULT 0",
+        "DO $$ BEGIN
+            IF NOT EXISTS (
+                SELECT 1 FROM information_schema.columns
+                WHERE table_name = 'replies' AND column_name = 'path'
+            ) THEN
+                ALTER TABLE replies ADD COLUMN path VARCHAR;
+                UPDATE replies SET path = id::text;
+                ALTER TABLE replies ALTER COLUMN path SET NOT NULL;
+            END IF;
+        END $$",
+        "ALTER TABLE replies ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP",
+        // posts, replies: scores, the sum of their votes
+        "ALTER TABLE posts ADD COLUMN IF NOT EXISTS score INTEGER NOT NULL DEFAULT 0",
+        "ALTER TABLE replies ADD COLUMN IF NOT EXISTS score INTEGER NOT NULL DEFAULT 0",
+        // votes: one per user per post and per reply, up or down
+        "CREATE UNIQUE INDEX IF NOT EXISTS \"idx-vote-user_id-post_id\" ON votes (user_id, post_id)
+            WHERE reply_id IS NULL",
+        "CREATE UNIQUE INDEX IF NOT EXISTS \"idx-vote-user_id-reply_id\" ON votes (user_id, reply_id)
+            WHERE reply_id IS NOT NULL",
+        "ALTER TABLE votes ADD CONSTRAINT \"check-vote-value\" CHECK (value IN (-1, 1))",
+        // posts, replies: listings page on (created_at, id)
+        "DO $$ BEGIN
+            IF EXISTS (
+                SELECT 1 FROM pg_indexes
+                WHERE indexname = 'idx-post-created_at' AND indexdef NOT LIKE '%(created_at, id)%'
+            ) THEN
+                DROP INDEX \"idx-post-created_at\";
+            END IF;
+            IF EXISTS (
+                SELECT 1 FROM pg_indexes
+                WHERE indexname = 'idx-reply-created_at' AND indexdef NOT LIKE '%(created_at, id)%'
+            ) THEN
+                DROP INDEX \"idx-reply-created_at\";
+            END IF;
+        END $$",
+        // posts, replies: full-text search, the expressions must match the search query
+        "DROP INDEX IF EXISTS \"idx-post-text\"",
+        "CREATE INDEX IF NOT EXISTS \"idx-post-search\" ON posts USING GIN ((
+            setweight(to_tsvector('english', title), 'A')
+                || setweight(to_tsvector('english', COALESCE(summary, '')), 'B')
+                || setweight(to_tsvector('english', text), 'C')
+        ))",
+        "DROP INDEX IF EXISTS \"idx-reply-text\"",
+        "CREATE INDEX IF NOT EXISTS \"idx-reply-search\" ON replies
+            USING GIN (to_tsvector('english', text))",
+        &audit_log_append_only,
+        "CREATE TRIGGER audit_log_no_update BEFORE UPDATE OR TRUNCATE ON audit_log
+            FOR EACH STATEMENT EXECUTE FUNCTION audit_log_append_only()",
+        "CREATE TRIGGER audit_log_retention BEFORE DELETE ON audit_log
+            FOR EACH ROW EXECUTE FUNCTION audit_log_append_only()",
+        "DROP TRIGGER IF EXISTS audit_log_append_only ON audit_log",
+        "CREATE INDEX IF NOT EXISTS \"idx-audit-username-lower\" ON audit_log (LOWER(username))",
+    ];
+    for sql in migrations {
+        let _ = db
+            .execute(Statement::from_string(builder, sql.to_owned()))
+            .await;
+    }
 
     let stmt = Index::create()
         .name("idx-user-username")
@@ -51,6 +367,89 @@ pub async fn init(db: &DatabaseConnection) {
         .build(PostgresQueryBuilder);
     let _ = db.execute(Statement::from_string(builder, stmt)).await;
 
+    let stmt = Index::create()
+        .name("idx-user-email")
+        .table(user::Entity)
+        .col(user::Column::Email)
+        .unique()
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-user-oidc_subject")
+        .table(user::Entity)
+        .col(user::Column::OidcSubject)
+        .unique()
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-user-invited_by")
+        .table(user::Entity)
+        .col(user::Column::InvitedBy)
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-invite-created_by")
+        .table(invite::Entity)
+        .col(invite::Column::CreatedBy)
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-audit-user_id")
+        .table(audit::Entity)
+        .col(audit::Column::UserId)
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-audit-ip")
+        .table(audit::Entity)
+        .col(audit::Column::Ip)
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-audit-created_at")
+        .table(audit::Entity)
+        .col(audit::Column::CreatedAt)
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-action_token-user_id")
+        .table(action_token::Entity)
+        .col(action_token::Column::UserId)
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-recovery_code-user_id")
+        .table(recovery_code::Entity)
+        .col(recovery_code::Column::UserId)
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-token-hash")
+        .table(token::Entity)
+        .col(token::Column::Hash)
+        .unique()
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
     let stmt = Index::create()
         .name("idx-token-user_id")
         .table(token::Entity)
@@ -76,10 +475,10 @@ pub async fn init(db: &DatabaseConnection) {
     let _ = db.execute(Statement::from_string(builder, stmt)).await;
 
     let stmt = Index::create()
-        .name("idx-reply-text")
+        .name("idx-reply-parent_reply_id")
         .table(reply::Entity)
-        .col(reply::Column::Text)
-        .index_type(IndexType::FullText)
+        .col(reply::Column::ParentReplyId)
+        .index_type(IndexType::BTree)
         .build(PostgresQueryBuilder);
     let _ = db.execute(Statement::from_string(builder, stmt)).await;
 
@@ -87,23 +486,59 @@ pub async fn init(db: &DatabaseConnection) {
         .name("idx-reply-created_at")
         .table(reply::Entity)
         .col(reply::Column::CreatedAt)
+        .col(reply::Column::Id)
         .index_type(IndexType::BTree)
         .build(PostgresQueryBuilder);
     let _ = db.execute(Statement::from_string(builder, stmt)).await;
 
     let stmt = Index::create()
-        .name("idx-post-user_id")
+        .name("idx-post_tag-tag_id")
+        .table(post_tag::Entity)
+        .col(post_tag::Column::TagId)
+        .col(post_tag::Column::PostId)
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-vote-post_id")
+        .table(vote::Entity)
+        .col(vote::Column::PostId)
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-vote-reply_id")
+        .table(vote::Entity)
+        .col(vote::Column::ReplyId)
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-post-board_id")
         .table(post::Entity)
-        .col(post::Column::UserId)
+        .col(post::Column::BoardId)
+        .col(post::Column::CreatedAt)
+        .col(post::Column::Id)
         .index_type(IndexType::BTree)
         .build(PostgresQueryBuilder);
     let _ = db.execute(Statement::from_string(builder, stmt)).await;
 
     let stmt = Index::create()
-        .name("idx-post-text")
+        .name("idx-board-parent_id")
+        .table(board::Entity)
+        .col(board::Column::ParentId)
+        .index_type(IndexType::BTree)
+        .build(PostgresQueryBuilder);
+    let _ = db.execute(Statement::from_string(builder, stmt)).await;
+
+    let stmt = Index::create()
+        .name("idx-post-user_id")
         .table(post::Entity)
-        .col(post::Column::Text)
-        .index_type(IndexType::FullText)
+        .col(post::Column::UserId)
+        .index_type(IndexType::BTree)
         .build(PostgresQueryBuilder);
     let _ = db.execute(Statement::from_string(builder, stmt)).await;
 
@@ -111,6 +546,7 @@ pub async fn init(db: &DatabaseConnection) {
         .name("idx-post-created_at")
         .table(post::Entity)
         .col(post::Column::CreatedAt)
+        .col(post::Column::Id)
         .index_type(IndexType::BTree)
         .build(PostgresQueryBuilder);
     let _ = db.execute(Statement::from_string(builder, stmt)).await;
@@ -0,0 +1,31 @@
+use super::*;
+
+// Actions a moderator can take on someone else's content or account
+pub const EDIT_POST: &str = "edit_post";
+pub const DELETE_POST: &str = "delete_post";
+pub const MOVE_POST: &str = "move_post";
+pub const EDIT_REPLY: &str = "edit_reply";
+pub const DELETE_REPLY: &str = "delete_reply";
+pub const SET_ROLE: &str = "set_role";
+pub const SET_STATUS: &str = "set_status";
+
+// Record of a moderator acting on another user's content
+// Kept without foreign keys so entries outlive what they describe
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "moderation_log")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    pub moderator_id: i64,
+    pub action: String,
+    pub target_user_id: i64,
+    pub post_id: Option<i64>,
+    pub reply_id: Option<i64>,
+    pub detail: Option<String>,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,200 @@
+use super::*;
+
+// How many items a page holds unless asked otherwise, and at most
+pub const DEFAULT_LIMIT: u64 = 20;
+pub const MAX_LIMIT: u64 = 100;
+
+const CURSOR_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f";
+
+// Query parameters selecting a page of a listing
+#[derive(Debug, Clone, Deserialize)]
+pub struct PageQuery {
+    // next_cursor of the previous page, omitted for the first page
+    pub cursor: Option<String>,
+    pub limit: Option<u64>,
+}
+
+impl PageQuery {
+    // Page size asked for, within 1 to MAX_LIMIT
+    pub fn limit(&self) -> u64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+}
+
+// A page of a listing, pass next_cursor back for the following page
+#[derive(Debug, Clone, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    // None on the last page
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    // Builds a page from up to limit + 1 rows, the extra row only tells there is more
+    pub fn new(mut items: Vec<T>, limit: u64, cursor: impl Fn(&T) -> Cursor) -> Self {
+        let more = items.len() as u64 > limit;
+        items.truncate(limit as usize);
+
+        let next_cursor = items
+            .last()
+            .filter(|_| more)
+            .map(|last| cursor(last).encode());
+
+        Page { items, next_cursor }
+    }
+}
+
+// Value a listing is ordered by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CursorKey {
+    Time(DateTime),
+    Count(i64),
+    // Search rank, then whether the hit is a reply
+    Rank(f32, bool),
+}
+
+// Position in a listing, the last item's sort key with ties broken by id
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    pub key: CursorKey,
+    pub id: i64,
+}
+
+impl Cursor {
+    pub fn time(created_at: DateTime, id: i64) -> Self {
+        Cursor {
+            key: CursorKey::Time(created_at),
+            id,
+        }
+    }
+
+    // Opaque URL-safe form handed to clients
+    pub fn encode(&self) -> String {
+        let key = match self.key {
+            CursorKey::Time(time) => format!("t{}", time.format(CURSOR_TIME_FORMAT)),
+            CursorKey::Count(count) => format!("n{}", count),
+            CursorKey::Rank(rank, reply) => format!("r{}:{}", rank, reply),
+        };
+        base64::encode_config(format!("{}_{}", key, self.id), base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let raw = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+        let raw = String::from_utf8(raw).ok()?;
+        let (key, id) = raw.split_once('_')?;
+
+        let key = if let Some(time) = key.strip_prefix('t') {
+            CursorKey::Time(DateTime::parse_from_str(time, CURSOR_TIME_FORMAT).ok()?)
+        } else if let Some(rank) = key.strip_prefix('r') {
+            let (rank, reply) = rank.split_once(':')?;
+            CursorKey::Rank(rank.parse().ok()?, reply.parse().ok()?)
+        } else {
+            CursorKey::Count(key.strip_prefix('n')?.parse().ok()?)
+        };
+
+        Some(Cursor {
+            key,
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time() -> DateTime {
+        DateTime::parse_from_str("2024-02-29T13:37:00.123456", CURSOR_TIME_FORMAT).unwrap()
+    }
+
+    fn encode_raw(raw: &str) -> String {
+        base64::encode_config(raw, base64::URL_SAFE_NO_PAD)
+    }
+
+    #[test]
+    fn cursor_round_trips_every_key() {
+        for cursor in [
+            Cursor::time(time(), 42),
+            Cursor {
+                key: CursorKey::Count(-7),
+                id: 1,
+            },
+            Cursor {
+                key: CursorKey::Rank(0.0607927, true),
+                id: i64::MAX,
+            },
+            Cursor {
+                key: CursorKey::Rank(1.5, false),
+                id: 3,
+            },
+        ] {
+            assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+        }
+    }
+
+    #[test]
+    fn cursor_is_url_safe() {
+        let encoded = Cursor::time(time(), 42).encode();
+        assert!(encoded
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    }
+
+    #[test]
+    fn cursor_rejects_tampered_input() {
+        let encoded = Cursor::time(time(), 42).encode();
+        let truncated = &encoded[..encoded.len() - 3];
+
+        for cursor in [
+            "",
+            "not base64!",
+            truncated,
+            &encode_raw("t2024-02-29T13:37:00.123456"),
+            &encode_raw("t2024-02-30T13:37:00.123456_42"),
+            &encode_raw("tyesterday_42"),
+            &encode_raw("n12_abc"),
+            &encode_raw("nmany_42"),
+            &encode_raw("x12_42"),
+            &encode_raw("12_42"),
+            &encode_raw("r0.5_42"),
+            &encode_raw("r0.5:maybe_42"),
+        ] {
+            assert_eq!(Cursor::decode(cursor), None, "{:?}", cursor);
+        }
+    }
+
+    #[test]
+    fn page_only_has_a_next_cursor_when_more_rows_came() {
+        let cursor = |id: &i64| Cursor {
+            key: CursorKey::Count(0),
+            id: *id,
+        };
+
+        let page = Page::new(vec![1, 2, 3], 3, cursor);
+        assert_eq!(page.items, [1, 2, 3]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = Page::new(vec![1, 2, 3, 4], 3, cursor);
+        assert_eq!(page.items, [1, 2, 3]);
+        assert_eq!(
+            page.next_cursor.as_deref().and_then(Cursor::decode),
+            Some(cursor(&3))
+        );
+    }
+
+    #[test]
+    fn page_query_limit_is_clamped() {
+        let limit = |limit| {
+            PageQuery {
+                cursor: None,
+                limit,
+            }
+            .limit()
+        };
+
+        assert_eq!(limit(None), DEFAULT_LIMIT);
+        assert_eq!(limit(Some(0)), 1);
+        assert_eq!(limit(Some(50)), 50);
+        assert_eq!(limit(Some(1000)), MAX_LIMIT);
+    }
+}
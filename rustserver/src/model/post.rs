@@ -1,8 +1,27 @@
 use super::*;
 
-#[derive(Debug, Clone, Deserialize, DeriveIntoActiveModel)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Input {
+    // Slug of the board, the default board when omitted
+    pub board: Option<String>,
+    pub title: String,
+    pub summary: Option<String>,
     pub text: String,
+    // Normalised before saving, duplicates are dropped
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+// Omitted fields are left alone, an empty summary clears it
+#[derive(Debug, Clone, Deserialize)]
+pub struct Update {
+    // Slug of the board to move the post to
+    pub board: Option<String>,
+    pub title: Option<String>,
+    pub summary: Option<String>,
+    pub text: Option<String>,
+    // Replaces all of the post's tags
+    pub tags: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, FromQueryResult)]
@@ -10,8 +29,49 @@ pub struct Output {
     pub id: i64,
     pub user_id: i64,
     pub username: String,
+    pub board_id: i64,
+    // Slug of the board
+    pub board: String,
+    pub title: String,
+    pub summary: Option<String>,
     pub text: String,
+    // Tag names, in alphabetical order
+    pub tags: Json,
     pub created_at: DateTime,
+    // None until the post is edited
+    pub updated_at: Option<DateTime>,
+    pub reply_count: i64,
+    // Upvotes less downvotes
+    pub score: i32,
+    // The caller's vote, None without one or when logged out
+    pub my_vote: Option<i32>,
+    // Time of the latest reply, or of the post itself without replies
+    pub last_activity_at: DateTime,
+}
+
+// Orders a post listing can take
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sort {
+    #[default]
+    Newest,
+    Oldest,
+    MostReplies,
+    RecentActivity,
+}
+
+// Query parameters ordering and narrowing down a post listing
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListQuery {
+    #[serde(default)]
+    pub sort: Sort,
+    // Username of the author, ignoring case
+    pub author: Option<String>,
+    pub created_after: Option<DateTime>,
+    pub created_before: Option<DateTime>,
+    pub has_replies: Option<bool>,
+    // Comma separated tags, posts with any of them match
+    pub tag: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
@@ -20,8 +80,14 @@ pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i64,
     pub user_id: i64,
+    pub board_id: i64,
+    pub title: String,
+    pub summary: Option<String>,
     pub text: String,
     pub created_at: DateTime,
+    pub updated_at: Option<DateTime>,
+    // Sum of the post's votes, kept in step with them
+    pub score: i32,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -34,8 +100,20 @@ pub enum Relation {
         on_delete = "Cascade"
     )]
     User,
+    #[sea_orm(
+        belongs_to = "super::board::Entity",
+        from = "Column::BoardId",
+        to = "super::board::Column::Id",
+        on_update = "Cascade",
+        on_delete = "Restrict"
+    )]
+    Board,
     #[sea_orm(has_many = "super::reply::Entity")]
     Reply,
+    #[sea_orm(has_many = "super::post_tag::Entity")]
+    PostTag,
+    #[sea_orm(has_many = "super::vote::Entity")]
+    Vote,
 }
 
 impl Related<super::user::Entity> for Entity {
@@ -43,10 +121,23 @@ impl Related<super::user::Entity> for Entity {
         Relation::User.def()
     }
 }
+impl Related<super::board::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Board.def()
+    }
+}
 impl Related<super::reply::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::Reply.def()
     }
 }
+impl Related<super::tag::Entity> for Entity {
+    fn to() -> RelationDef {
+        super::post_tag::Relation::Tag.def()
+    }
+    fn via() -> Option<RelationDef> {
+        Some(super::post_tag::Relation::Post.def().rev())
+    }
+}
 
 impl ActiveModelBehavior for ActiveModel {}
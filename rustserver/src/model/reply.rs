@@ -1,7 +1,14 @@
 use super::*;
 
-#[derive(Debug, Clone, Deserialize, DeriveIntoActiveModel)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Input {
+    // Reply this one answers, on the same post, None for a top-level reply
+    pub parent_reply_id: Option<i64>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Deserialize, DeriveIntoActiveModel)]
+pub struct Update {
     pub text: String,
 }
 
@@ -9,10 +16,48 @@ pub struct Input {
 pub struct Output {
     pub id: i64,
     pub post_id: i64,
-    pub user_id: i64,
-    pub username: String,
+    pub parent_reply_id: Option<i64>,
+    // 0 for top-level replies
+    pub depth: i32,
+    // Ids from the top-level reply down to this one, joined by '/'
+    pub path: String,
+    // Author, None on placeholders
+    pub user_id: Option<i64>,
+    pub username: Option<String>,
+    // Empty once deleted
     pub text: String,
     pub created_at: DateTime,
+    // Set when the reply was deleted but stays as a placeholder for its children
+    pub deleted_at: Option<DateTime>,
+    // Upvotes less downvotes
+    pub score: i32,
+    // The caller's vote, None without one or when logged out
+    pub my_vote: Option<i32>,
+}
+
+// A reply with the replies below it
+#[derive(Debug, Clone, Serialize)]
+pub struct Node {
+    #[serde(flatten)]
+    pub reply: Output,
+    pub children: Vec<Node>,
+}
+
+// Shapes a reply listing can take
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum View {
+    // Every reply, oldest first
+    #[default]
+    Flat,
+    // Top-level replies, oldest first, each with the replies below it nested as children
+    Tree,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListQuery {
+    #[serde(default)]
+    pub view: View,
 }
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
@@ -21,9 +66,15 @@ pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i64,
     pub post_id: i64,
+    pub parent_reply_id: Option<i64>,
+    pub depth: i32,
+    pub path: String,
     pub user_id: i64,
     pub text: String,
     pub created_at: DateTime,
+    pub deleted_at: Option<DateTime>,
+    // Sum of the reply's votes, kept in step with them
+    pub score: i32,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -44,6 +95,17 @@ pub enum Relation {
         on_delete = "Cascade"
     )]
     Post,
+    // Replies with replies below them are kept as placeholders, never deleted
+    #[sea_orm(
+        belongs_to = "Entity",
+        from = "Column::ParentReplyId",
+        to = "Column::Id",
+        on_update = "Cascade",
+        on_delete = "Restrict"
+    )]
+    Parent,
+    #[sea_orm(has_many = "super::vote::Entity")]
+    Vote,
 }
 
 impl Related<super::user::Entity> for Entity {
@@ -0,0 +1,25 @@
+use super::*;
+
+// Query parameters of a search
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchQuery {
+    // Words to look for, quoted phrases, OR and -word work as on web search engines
+    pub q: String,
+}
+
+// A post or reply matching a search
+#[derive(Debug, Clone, Serialize, FromQueryResult)]
+pub struct Hit {
+    // "post" or "reply"
+    pub kind: String,
+    pub id: i64,
+    // The post itself, or the post replied to, and its title
+    pub post_id: i64,
+    pub title: String,
+    pub user_id: i64,
+    pub username: String,
+    // HTML escaped excerpt with the matches wrapped in <mark>
+    pub snippet: String,
+    pub rank: f32,
+    pub created_at: DateTime,
+}
@@ -0,0 +1,35 @@
+use super::*;
+
+#[derive(Debug, Clone, Serialize, FromQueryResult)]
+pub struct Output {
+    pub name: String,
+    pub post_count: i64,
+}
+
+// A normalised free-form label, attached to posts through post_tags
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "tags")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    #[sea_orm(unique)]
+    pub name: String,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(has_many = "super::post_tag::Entity")]
+    PostTag,
+}
+
+impl Related<super::post::Entity> for Entity {
+    fn to() -> RelationDef {
+        super::post_tag::Relation::Post.def()
+    }
+    fn via() -> Option<RelationDef> {
+        Some(super::post_tag::Relation::Tag.def().rev())
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1,12 +1,138 @@
 use super::*;
 
+// Prefix marking personal access tokens, so they are easy to spot in scripts and logs
+pub const ACCESS_TOKEN_PREFIX: &str = "afp_";
+
+// What a personal access token may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "posts:read")]
+    PostsRead,
+    #[serde(rename = "posts:write")]
+    PostsWrite,
+    #[serde(rename = "replies:write")]
+    RepliesWrite,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::PostsRead => "posts:read",
+            Scope::PostsWrite => "posts:write",
+            Scope::RepliesWrite => "replies:write",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccessTokenInput {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessToken {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime,
+    pub last_used_at: DateTime,
+    pub expires_at: DateTime,
+}
+
+// Returned once on creation, the only time the secret is visible
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedAccessToken {
+    #[serde(flatten)]
+    pub access_token: AccessToken,
+    pub token: String,
+}
+
+// Formats the secret of a personal access token
+pub fn format_access_token(uuid: Uuid) -> String {
+    format!("{}{}", ACCESS_TOKEN_PREFIX, uuid.to_simple())
+}
+
+// Parses the secret of a personal access token
+pub fn parse_access_token(token: &str) -> Option<Uuid> {
+    token
+        .strip_prefix(ACCESS_TOKEN_PREFIX)
+        .and_then(|t| Uuid::parse_str(t).ok())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    pub id: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime,
+    pub last_used_at: DateTime,
+    pub expires_at: DateTime,
+    pub current: bool,
+}
+
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "tokens")]
 pub struct Model {
-    #[sea_orm(primary_key, auto_increment = false, column_type = "Char(Some(60))")]
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    #[sea_orm(unique, column_type = "String(Some(64))")]
     pub hash: String,
     pub user_id: i64,
     pub expires_at: DateTime,
+    pub created_at: DateTime,
+    pub last_used_at: DateTime,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    // Set for personal access tokens, None for login sessions
+    pub name: Option<String>,
+    // Space separated scopes of personal access tokens, None for login sessions
+    pub scopes: Option<String>,
+}
+
+impl Model {
+    pub fn is_access_token(&self) -> bool {
+        self.scopes.is_some()
+    }
+
+    // Whether the token may be used for scope, login sessions may do anything
+    pub fn allows(&self, scope: Scope) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.split(' ').any(|s| s == scope.as_str()),
+            None => true,
+        }
+    }
+
+    pub fn to_access_token(&self) -> AccessToken {
+        AccessToken {
+            id: self.id,
+            name: self.name.clone().unwrap_or_default(),
+            scopes: self
+                .scopes
+                .as_deref()
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect(),
+            created_at: self.created_at,
+            last_used_at: self.last_used_at,
+            expires_at: self.expires_at,
+        }
+    }
+
+    // Describes this token as a session, current is whether it authenticated the request
+    pub fn to_session(&self, current: bool) -> Session {
+        Session {
+            id: self.id,
+            user_agent: self.user_agent.clone(),
+            ip: self.ip.clone(),
+            created_at: self.created_at,
+            last_used_at: self.last_used_at,
+            expires_at: self.expires_at,
+            current,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -6,12 +6,177 @@ pub struct LoginResponse {
     pub message: &'static str,
 }
 
-#[derive(Debug, Clone, Deserialize, DeriveIntoActiveModel)]
+// A rule a field of a request failed
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+
+// Returned by POST /register, listing every rule the input failed
+#[derive(Debug, Clone, Serialize)]
+pub struct RegisterResponse {
+    pub status: bool,
+    pub message: &'static str,
+    pub errors: Vec<FieldError>,
+}
+
+// Returned by POST /login when a second factor is needed
+#[derive(Debug, Clone, Serialize)]
+pub struct ChallengeResponse {
+    pub status: bool,
+    pub message: &'static str,
+    pub challenge: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChallengeInput {
+    pub challenge: String,
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PasswordConfirm {
+    // Left out by accounts that only sign in through SSO
+    #[serde(default)]
+    pub password: String,
+    // TOTP or recovery code, confirming instead of the password on SSO-only accounts
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TwoFactorCode {
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TwoFactorEnrolment {
+    pub secret: String,
+    pub otpauth_uri: String,
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Input {
     pub username: String,
     pub password: String,
 }
 
+// Taken by POST /register, the invite is needed while registration is invite-only
+#[derive(Debug, Clone, Deserialize)]
+pub struct Registration {
+    pub username: String,
+    pub password: String,
+    pub email: Option<String>,
+    pub invite: Option<String>,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    EnumIter,
+    DeriveActiveEnum,
+    Serialize,
+    Deserialize,
+)]
+#[sea_orm(rs_type = "String", db_type = "String(None)")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[sea_orm(string_value = "user")]
+    User,
+    #[sea_orm(string_value = "moderator")]
+    Moderator,
+    #[sea_orm(string_value = "admin")]
+    Admin,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoleUpdate {
+    pub role: Role,
+}
+
+// Whether an account may be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "String(None)")]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    #[sea_orm(string_value = "pending_verification")]
+    PendingVerification,
+    #[sea_orm(string_value = "active")]
+    Active,
+    #[sea_orm(string_value = "suspended")]
+    Suspended,
+    #[sea_orm(string_value = "banned")]
+    Banned,
+}
+
+// Suspensions without an end last until lifted
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatusUpdate {
+    pub status: Status,
+    pub suspended_until: Option<DateTime>,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VerificationRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VerificationConfirm {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProfileUpdate {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, FromQueryResult)]
+pub struct Profile {
+    pub id: i64,
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub role: Role,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suspended_until: Option<DateTime>,
+    // Username of whoever invited the user, left out of public profiles
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invited_by: Option<String>,
+    pub created_at: DateTime,
+    pub post_count: i64,
+    pub reply_count: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PasswordChange {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResetRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResetConfirm {
+    pub token: String,
+    pub password: String,
+}
+
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "users")]
 pub struct Model {
@@ -19,11 +184,46 @@ pub struct Model {
     pub id: i64,
     #[sea_orm(unique)]
     pub username: String,
-    #[sea_orm(column_type = "Char(Some(60))")]
     pub password: String,
+    #[sea_orm(unique)]
+    pub email: Option<String>,
+    // When the user proved they own the email address, by verification link or provider claim
+    pub email_verified_at: Option<DateTime>,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub role: Role,
+    // Base32 TOTP secret, set from enrolment on
+    pub totp_secret: Option<String>,
+    // Whether enrolment was confirmed and logins need a second factor
+    pub totp_enabled: bool,
+    // Last TOTP time step accepted, so codes can't be replayed
+    pub totp_last_step: Option<i64>,
+    // Subject claim of the identity provider account linked for SSO
+    #[sea_orm(unique)]
+    pub oidc_subject: Option<String>,
+    pub status: Status,
+    // End of a suspension, None while suspended means until lifted
+    pub suspended_until: Option<DateTime>,
+    // Who invited the user and with which invite, kept without foreign keys for tracing
+    pub invited_by: Option<i64>,
+    pub invite_id: Option<i64>,
     pub created_at: DateTime,
 }
 
+impl Model {
+    // Why the account can't be used at the moment, None when it can
+    // Suspensions that ran out count as lifted
+    pub fn status_error(&self, now: DateTime) -> Option<&'static str> {
+        match self.status {
+            Status::Active => None,
+            Status::PendingVerification => Some("account is pending email verification"),
+            Status::Suspended if self.suspended_until.is_some_and(|until| until <= now) => None,
+            Status::Suspended => Some("account is suspended"),
+            Status::Banned => Some("account is banned"),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
     #[sea_orm(has_many = "super::token::Entity")]
@@ -32,6 +232,12 @@ pub enum Relation {
     Post,
     #[sea_orm(has_many = "super::reply::Entity")]
     Reply,
+    #[sea_orm(has_many = "super::action_token::Entity")]
+    ActionToken,
+    #[sea_orm(has_many = "super::recovery_code::Entity")]
+    RecoveryCode,
+    #[sea_orm(has_many = "super::invite::Entity")]
+    Invite,
 }
 
 impl Related<super::token::Entity> for Entity {
@@ -50,4 +256,22 @@ impl Related<super::reply::Entity> for Entity {
     }
 }
 
+impl Related<super::action_token::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ActionToken.def()
+    }
+}
+
+impl Related<super::recovery_code::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::RecoveryCode.def()
+    }
+}
+
+impl Related<super::invite::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Invite.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,75 @@
+use super::*;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Input {
+    // 1 for an upvote, -1 for a downvote
+    pub value: i32,
+}
+
+// Score of the voted post or reply after the vote
+#[derive(Debug, Clone, Serialize)]
+pub struct Output {
+    pub score: i32,
+    // None once retracted
+    pub my_vote: Option<i32>,
+}
+
+// A user's vote on a post, or on one of its replies when reply_id is set
+// Each user has at most one vote per post and per reply
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "votes")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    pub user_id: i64,
+    pub post_id: i64,
+    pub reply_id: Option<i64>,
+    pub value: i32,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::user::Entity",
+        from = "Column::UserId",
+        to = "super::user::Column::Id",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    User,
+    #[sea_orm(
+        belongs_to = "super::post::Entity",
+        from = "Column::PostId",
+        to = "super::post::Column::Id",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    Post,
+    #[sea_orm(
+        belongs_to = "super::reply::Entity",
+        from = "Column::ReplyId",
+        to = "super::reply::Column::Id",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    Reply,
+}
+
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
+impl Related<super::post::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Post.def()
+    }
+}
+impl Related<super::reply::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Reply.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
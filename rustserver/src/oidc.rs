@@ -0,0 +1,186 @@
+use awc::{Client, ClientResponse};
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Deserialize};
+use url::Url;
+
+use crate::config::OidcConfig;
+
+// The provider metadata used by the authorization-code flow
+#[derive(Debug, Clone, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+// The ID token claims aeroFans uses
+#[derive(Debug, Clone, Deserialize)]
+pub struct Claims {
+    iss: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    pub sub: String,
+    pub preferred_username: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+}
+
+// Provider URLs must use https, unless OIDC_ALLOW_INSECURE_HTTP is set for a local mock IdP
+fn parse_url(config: &OidcConfig, url: &str) -> Result<Url, String> {
+    let parsed = Url::parse(url).map_err(|e| format!("invalid provider url {}: {}", url, e))?;
+
+    match parsed.scheme() {
+        "https" => Ok(parsed),
+        "http" if config.allow_insecure_http => Ok(parsed),
+        _ => Err(format!("provider url {} does not use https", url)),
+    }
+}
+
+async fn read_json<T: DeserializeOwned, S>(mut response: ClientResponse<S>) -> Result<T, String>
+where
+    S: futures::Stream<Item = Result<actix_web::web::Bytes, awc::error::PayloadError>> + Unpin,
+{
+    let body = response
+        .body()
+        .await
+        .map_err(|e| format!("invalid provider response: {}", e))?;
+
+    if !response.status().is_success() {
+        return Err(format!(
+            "provider answered {}: {}",
+            response.status(),
+            String::from_utf8_lossy(&body)
+        ));
+    }
+
+    serde_json::from_slice(&body).map_err(|e| format!("invalid provider response: {}", e))
+}
+
+// Fetches the provider metadata from below the issuer URL
+pub async fn discover(config: &OidcConfig) -> Result<Discovery, String> {
+    parse_url(config, &config.issuer)?;
+    let url = parse_url(
+        config,
+        &format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        ),
+    )?;
+
+    let response = Client::default()
+        .get(url.as_str())
+        .send()
+        .await
+        .map_err(|e| format!("provider discovery failed: {}", e))?;
+    let discovery: Discovery = read_json(response).await?;
+
+    if discovery.issuer != config.issuer {
+        return Err(String::from("provider issuer does not match OIDC_ISSUER"));
+    }
+    parse_url(config, &discovery.authorization_endpoint)?;
+    parse_url(config, &discovery.token_endpoint)?;
+
+    Ok(discovery)
+}
+
+// Builds the URL sending the browser to the provider's login
+pub fn authorization_url(
+    discovery: &Discovery,
+    config: &OidcConfig,
+    state: &str,
+    nonce: &str,
+    code_challenge: &str,
+) -> Result<String, String> {
+    let url = Url::parse_with_params(
+        &discovery.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", config.client_id.as_str()),
+            ("redirect_uri", config.redirect_url.as_str()),
+            ("scope", config.scopes.as_str()),
+            ("state", state),
+            ("nonce", nonce),
+            ("code_challenge", code_challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|e| format!("invalid authorization endpoint: {}", e))?;
+
+    Ok(url.into())
+}
+
+// Redeems an authorization code and returns the checked ID token claims
+// The ID token comes straight from the token endpoint over https, so its signature isn't checked,
+// the TLS connection is trusted instead (OpenID Connect Core 3.1.3.7)
+pub async fn exchange_code(
+    discovery: &Discovery,
+    config: &OidcConfig,
+    code: &str,
+    code_verifier: &str,
+    nonce: &str,
+) -> Result<Claims, String> {
+    let url = parse_url(config, &discovery.token_endpoint)?;
+
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", config.redirect_url.as_str()),
+        ("client_id", config.client_id.as_str()),
+        ("code_verifier", code_verifier),
+    ];
+    if let Some(secret) = &config.client_secret {
+        form.push(("client_secret", secret.as_str()));
+    }
+
+    let response = Client::default()
+        .post(url.as_str())
+        .send_form(&form)
+        .await
+        .map_err(|e| format!("token request failed: {}", e))?;
+    let token: TokenResponse = read_json(response).await?;
+
+    let claims = decode_claims(&token.id_token)?;
+
+    let audience_ok = match &claims.aud {
+        Audience::One(aud) => *aud == config.client_id,
+        Audience::Many(auds) => auds.contains(&config.client_id),
+    };
+
+    if claims.iss != discovery.issuer {
+        Err(String::from("ID token has the wrong issuer"))
+    } else if !audience_ok {
+        Err(String::from("ID token has the wrong audience"))
+    } else if claims.exp <= Utc::now().timestamp() {
+        Err(String::from("ID token has expired"))
+    } else if claims.nonce.as_deref() != Some(nonce) {
+        Err(String::from("ID token has the wrong nonce"))
+    } else {
+        Ok(claims)
+    }
+}
+
+// Reads the payload of a compact JWT
+fn decode_claims(id_token: &str) -> Result<Claims, String> {
+    let payload = id_token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| String::from("ID token is not a JWT"))?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| String::from("ID token is not a JWT"))?;
+
+    serde_json::from_slice(&payload).map_err(|e| format!("invalid ID token claims: {}", e))
+}